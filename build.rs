@@ -0,0 +1,165 @@
+extern crate time;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(&["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_owned())
+        .and_then(|commit| if commit.is_empty() { None } else { Some(commit) })
+        .unwrap_or(String::from("unknown"));
+
+    let built_at = time::now_utc().rfc3339().to_string();
+
+    write_migrations();
+    write_assets();
+
+    println!("cargo:rustc-env=PUPIL_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=PUPIL_BUILD_TIME={}", built_at);
+    println!("cargo:rerun-if-changed=migrations");
+    watch_git_head();
+}
+
+/// HEAD itself only changes when switching branches; a new commit moves the
+/// branch it points at, which is either a loose ref or in `packed-refs`.
+fn watch_git_head() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+
+    let head = File::open(".git/HEAD").ok().and_then(|mut file| {
+        let mut head = String::new();
+        file.read_to_string(&mut head).ok().map(|_| head)
+    });
+
+    if let Some(head) = head {
+        if head.starts_with("ref: ") {
+            let path = Path::new(".git").join(head["ref: ".len()..].trim());
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+
+    if Path::new(".git/packed-refs").exists() {
+        println!("cargo:rerun-if-changed=.git/packed-refs");
+    }
+}
+
+/// The SQL sqlite should run for `name`: `<name>.sqlite.sql` if there is one,
+/// otherwise the same `<name>.sql` as postgres.
+fn sqlite_file(dir: &str, name: &str) -> String {
+    let sqlite = format!("{}/{}.sqlite.sql", dir, name);
+    if Path::new(&sqlite).exists() {
+        sqlite
+    } else {
+        format!("{}/{}.sql", dir, name)
+    }
+}
+
+/// Embeds every directory under `migrations/` into the binary as a
+/// `MIGRATIONS` table, sorted by version, for `src/migrations.rs` to include.
+fn write_migrations() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    let mut migrations: Vec<(String, String)> = fs::read_dir(Path::new(&manifest_dir)
+            .join("migrations"))
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let path = entry.path().to_string_lossy().into_owned();
+            entry.file_name()
+                .into_string()
+                .ok()
+                .and_then(|name| name.split('_').next().map(String::from))
+                .map(|version| (version, path))
+        })
+        .collect();
+    migrations.sort();
+
+    let mut file = File::create(Path::new(&out_dir).join("migrations.rs")).unwrap();
+    writeln!(file, "pub static MIGRATIONS: &'static [Migration] = &[").unwrap();
+    for (version, path) in migrations {
+        // a sqlite variant added later only shows up as a change to its directory
+        println!("cargo:rerun-if-changed={}", path);
+        writeln!(file, "    Migration {{").unwrap();
+        writeln!(file, "        version: {:?},", version).unwrap();
+        writeln!(file, "        up: include_str!({:?}),", format!("{}/up.sql", path)).unwrap();
+        writeln!(file, "        down: include_str!({:?}),", format!("{}/down.sql", path)).unwrap();
+        writeln!(file, "        sqlite_up: include_str!({:?}),", sqlite_file(&path, "up")).unwrap();
+        writeln!(file,
+                 "        sqlite_down: include_str!({:?}),",
+                 sqlite_file(&path, "down"))
+            .unwrap();
+        writeln!(file, "    }},").unwrap();
+    }
+    writeln!(file, "];").unwrap();
+}
+
+/// Collects every file under `dir`, and every directory it passes through.
+fn files_under(dir: &Path, files: &mut Vec<PathBuf>, dirs: &mut Vec<PathBuf>) {
+    dirs.push(dir.to_path_buf());
+
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files_under(&path, files, dirs);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// With the `embed-assets` feature, embeds every file under `static/` as an
+/// `EMBEDDED` table of paths relative to it, for `src/assets.rs` to include.
+/// Without it the table is empty.
+fn write_assets() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    let mut file = File::create(Path::new(&out_dir).join("assets.rs")).unwrap();
+    writeln!(file, "pub static EMBEDDED_AT: u64 = {};", time::get_time().sec).unwrap();
+    writeln!(file, "pub static EMBEDDED: &'static [(&'static str, &'static [u8])] = &[").unwrap();
+
+    if env::var_os("CARGO_FEATURE_EMBED_ASSETS").is_some() {
+        let root = Path::new(&manifest_dir).join("static");
+        if !root.is_dir() {
+            panic!("embed-assets needs the frontend built into {}", root.display());
+        }
+
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        files_under(&root, &mut files, &mut dirs);
+        files.sort();
+
+        // cargo only looks at what is listed, and a directory's mtime only
+        // changes when an entry is added or removed right inside it, so
+        // edits to files and files added in subdirectories need their own
+        for dir in &dirs {
+            println!("cargo:rerun-if-changed={}", dir.display());
+        }
+
+        for path in files {
+            println!("cargo:rerun-if-changed={}", path.display());
+            let relative: Vec<String> = path.strip_prefix(&root)
+                .unwrap()
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            writeln!(file,
+                     "    ({:?}, include_bytes!({:?}) as &'static [u8]),",
+                     relative.join("/"),
+                     path.display().to_string())
+                .unwrap();
+        }
+    }
+
+    writeln!(file, "];").unwrap();
+}
@@ -104,7 +104,7 @@ update msg model =
                             Just "Check network connection."
 
                         Http.BadStatus response ->
-                            Just response.body
+                            Just (errorMessage response.body)
 
                         Http.BadPayload string response ->
                             Just "Server response was unexpected. Please try again later."
@@ -261,6 +261,11 @@ subscriptions model =
 -- HTTP
 
 
+errorMessage body =
+    Json.Decode.decodeString (Json.Decode.field "error" Json.Decode.string) body
+        |> Result.withDefault body
+
+
 submitLogin model =
     Http.send
         Response
@@ -0,0 +1,8 @@
+create table users (
+  id integer primary key autoincrement,
+  name varchar not null,
+  email varchar not null unique,
+  username varchar not null unique,
+  pass varchar not null,
+  conf boolean not null default 0
+)
@@ -0,0 +1,12 @@
+create table users_without_roles (
+  id integer primary key autoincrement,
+  name varchar not null,
+  email varchar not null unique,
+  username varchar not null unique,
+  pass varchar not null,
+  conf boolean not null default 0
+);
+insert into users_without_roles (id, name, email, username, pass, conf)
+  select id, name, email, username, pass, conf from users;
+drop table users;
+alter table users_without_roles rename to users
@@ -0,0 +1 @@
+alter table users add column role varchar not null default 'student'
@@ -0,0 +1,9 @@
+create table access_tokens (
+  id serial primary key,
+  user_id integer not null references users (id) on delete cascade,
+  name varchar not null,
+  token_hash varchar not null unique,
+  scopes varchar not null,
+  created_at bigint not null,
+  revoked boolean not null default 'f'
+)
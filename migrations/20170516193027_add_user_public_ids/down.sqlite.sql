@@ -0,0 +1,28 @@
+create table access_tokens_before_public_ids as select * from access_tokens;
+drop table access_tokens;
+create table users_without_public_ids (
+  id integer primary key autoincrement,
+  name varchar not null,
+  email varchar not null unique,
+  username varchar not null unique,
+  pass varchar not null,
+  conf boolean not null default 0,
+  role varchar not null default 'student'
+);
+insert into users_without_public_ids (id, name, email, username, pass, conf, role)
+  select id, name, email, username, pass, conf, role from users;
+drop table users;
+alter table users_without_public_ids rename to users;
+create table access_tokens (
+  id integer primary key autoincrement,
+  user_id integer not null references users (id) on delete cascade,
+  name varchar not null,
+  token_hash varchar not null unique,
+  scopes varchar not null,
+  created_at bigint not null,
+  revoked boolean not null default 0
+);
+insert into access_tokens (id, user_id, name, token_hash, scopes, created_at, revoked)
+  select id, user_id, name, token_hash, scopes, created_at, revoked
+  from access_tokens_before_public_ids;
+drop table access_tokens_before_public_ids
@@ -0,0 +1,498 @@
+use time;
+
+use rocket::http::Cookies;
+use rocket::State;
+use rocket_contrib::JSON;
+
+use super::model::{Me, UpdateUser, UserChanges, ChangePassword, DeleteAccount, NewAccessToken,
+                   CreateToken, CreatedToken, TokenInfo};
+use super::error::{Error, ThresholdKind};
+use super::auth::{self, Auth, Scope};
+use super::hasher::Hasher;
+use super::store::Store;
+use super::export::{Export, ExportToken};
+use super::config::Config;
+use super::session::AuthCookie;
+use super::keys::JwtKeys;
+
+#[get("/me")]
+fn me(auth: Auth, store: State<Store>) -> Result<JSON<Me>, Error> {
+    auth.require(Scope::Read)?;
+
+    let current = store.users().find(&auth.user.id)?;
+
+    Ok(JSON(Me::new(current)))
+}
+
+#[patch("/me", format = "application/json", data = "<data>")]
+fn update_me(auth: Auth,
+             mut cookies: Cookies,
+             data: JSON<UpdateUser>,
+             store: State<Store>,
+             config: State<Config>,
+             keys: State<JwtKeys>)
+             -> Result<JSON<String>, Error> {
+    auth.require(Scope::Write)?;
+    auth.require(Scope::Account)?;
+    let user = auth.user;
+    let data = data.into_inner();
+
+    let current = store.users().find(&user.id)?;
+
+    // a new email address has to be confirmed again before the user can get back in,
+    // so the session ends here rather than carrying on with a stale `conf` claim
+    let email_changed = match data.email {
+        Some(ref email) => *email != current.email,
+        None => false,
+    };
+
+    let changes = UserChanges {
+        name: data.name.as_ref().map(String::as_str),
+        email: data.email.as_ref().map(String::as_str),
+        username: data.username.as_ref().map(String::as_str),
+        conf: if email_changed { Some(false) } else { None },
+    };
+
+    let updated = if changes.name.is_none() && changes.email.is_none() &&
+                     changes.username.is_none() {
+        current
+    } else {
+        store.users().update(current.id, &changes)?
+    };
+
+    if email_changed {
+        AuthCookie::new(&config).remove(&mut cookies);
+        Err(Error::NotConfirmed(ThresholdKind::EmailChange))
+    } else {
+        AuthCookie::new(&config).issue(&mut cookies, updated, &keys)?;
+        Ok(JSON(String::from("dash")))
+    }
+
+    // TODO: send confirmation email to the new address
+}
+
+#[post("/me/password", format = "application/json", data = "<data>")]
+fn change_password(auth: Auth,
+                   data: JSON<ChangePassword>,
+                   store: State<Store>,
+                   hasher: State<Hasher>,
+                   config: State<Config>)
+                   -> Result<JSON<String>, Error> {
+    auth.require(Scope::Write)?;
+    auth.require(Scope::Account)?;
+    let user = auth.user;
+    let data = data.into_inner();
+
+    let current = store.users().find(&user.id)?;
+
+    if !hasher.verify_password(current.pass.as_str(), data.current_password.as_str())? {
+        return Err(Error::WrongPassword);
+    }
+
+    let secure_pass = hasher.hash_password(current.username.as_str(),
+                                           data.new_password.as_str(),
+                                           config.hash_secret.as_str())?;
+
+    store.users().set_password(current.id, secure_pass.as_str())?;
+
+    Ok(JSON(String::from("dash")))
+}
+
+#[delete("/me", format = "application/json", data = "<data>")]
+fn delete_me(auth: Auth,
+             mut cookies: Cookies,
+             data: JSON<DeleteAccount>,
+             store: State<Store>,
+             hasher: State<Hasher>,
+             config: State<Config>)
+             -> Result<JSON<String>, Error> {
+    auth.require(Scope::Write)?;
+    auth.require(Scope::Account)?;
+    let user = auth.user;
+    let data = data.into_inner();
+
+    let current = store.users().find(&user.id)?;
+
+    if !hasher.verify_password(current.pass.as_str(), data.password.as_str())? {
+        return Err(Error::WrongPassword);
+    }
+
+    store.users().delete(current.id)?;
+
+    AuthCookie::new(&config).remove(&mut cookies);
+    Ok(JSON(String::from("/")))
+}
+
+#[get("/me/export")]
+fn export_me(auth: Auth, store: State<Store>) -> Result<Export, Error> {
+    auth.require(Scope::Account)?;
+
+    let current = store.users().find(&auth.user.id)?;
+
+    Ok(Export::new(current))
+}
+
+#[post("/me/export/link")]
+fn export_link(auth: Auth, keys: State<JwtKeys>) -> Result<JSON<String>, Error> {
+    auth.require(Scope::Account)?;
+
+    let token = ExportToken::new(&auth.user.id).construct_jwt(&keys)?;
+
+    Ok(JSON(format!("/api/v1/export/{}", token)))
+}
+
+#[get("/export/<token>")]
+fn export_download(token: String,
+                   store: State<Store>,
+                   keys: State<JwtKeys>)
+                   -> Result<Export, Error> {
+    let id = match ExportToken::verify(token.as_str(), &keys) {
+        Some(id) => id,
+        None => return Err(Error::BadExportLink),
+    };
+
+    let current = store.users().find(&id)?;
+
+    Ok(Export::new(current))
+}
+
+#[get("/tokens")]
+fn tokens(auth: Auth, store: State<Store>) -> Result<JSON<Vec<TokenInfo>>, Error> {
+    auth.require(Scope::Account)?;
+
+    let current = store.users().find(&auth.user.id)?;
+    let found = store.access_tokens().list(current.id)?;
+
+    Ok(JSON(found.into_iter().map(TokenInfo::from).collect()))
+}
+
+#[post("/tokens", format = "application/json", data = "<data>")]
+fn create_token(auth: Auth,
+                data: JSON<CreateToken>,
+                store: State<Store>)
+                -> Result<JSON<CreatedToken>, Error> {
+    auth.require(Scope::Write)?;
+    auth.require(Scope::Account)?;
+
+    let data = data.into_inner();
+
+    // access tokens must never be able to manage the account they belong to
+    if data.scopes.is_empty() || data.scopes.contains(&Scope::Account) {
+        return Err(Error::MissingScope);
+    }
+
+    let current = store.users().find(&auth.user.id)?;
+    let (token, hash) = auth::generate_access_token();
+    let scopes = Scope::join(&data.scopes);
+
+    let new_token = NewAccessToken {
+        user_id: current.id,
+        name: data.name.as_str(),
+        token_hash: hash.as_str(),
+        scopes: scopes.as_str(),
+        created_at: time::get_time().sec,
+    };
+
+    let created = store.access_tokens().create(&new_token)?;
+
+    Ok(JSON(CreatedToken {
+        info: TokenInfo::from(created),
+        token: token,
+    }))
+}
+
+#[delete("/tokens/<id>")]
+fn revoke_token(auth: Auth, id: i32, store: State<Store>) -> Result<JSON<String>, Error> {
+    auth.require(Scope::Write)?;
+    auth.require(Scope::Account)?;
+
+    let current = store.users().find(&auth.user.id)?;
+    store.access_tokens().revoke(current.id, id)?;
+
+    Ok(JSON(String::from("revoked")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::model::{User, Role, ChangePassword, DeleteAccount, UpdateUser};
+    use super::super::error::{Error, ThresholdKind};
+    use super::super::schema::{users, access_tokens};
+    use super::super::testing::{TestDb, UserBuilder, PASSWORD, error_message};
+
+    use std::error::Error as StdError;
+    use std::ops::Deref;
+
+    use rocket::testing::MockRequest;
+    use rocket::http::{Status, Method, ContentType, Cookie, Header};
+
+    use diesel;
+    use diesel::prelude::*;
+
+    use serde_json;
+
+    #[test]
+    fn me() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me]);
+        let mut req = db.authed(Method::Get, "/api/v1/me", &jsmith);
+        let mut response = req.dispatch_with(&rocket);
+
+        let body: Option<serde_json::Value> = response.body()
+            .and_then(|b| b.into_string())
+            .and_then(|b| serde_json::from_str(&b).ok());
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(body,
+                   Some(json!({
+                       "user": {
+                           "id": jsmith.public_id,
+                           "name": "John Smith",
+                           "email": "jsmith@website.com",
+                           "username": "jsmith",
+                           "conf": true
+                       },
+                       "role": "student",
+                       "profile_completeness": 100,
+                       "missing": []
+                   })));
+    }
+
+    #[test]
+    fn me_reports_role() {
+        let db = TestDb::new();
+        let tutor = UserBuilder::new("tutor").role(Role::Tutor).create(&db);
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me]);
+        let mut req = db.authed(Method::Get, "/api/v1/me", &tutor);
+        let mut response = req.dispatch_with(&rocket);
+
+        let body: serde_json::Value = response.body()
+            .and_then(|b| b.into_string())
+            .and_then(|b| serde_json::from_str(&b).ok())
+            .unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(body["role"], json!("tutor"));
+    }
+
+    #[test]
+    fn me_bearer() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me]);
+        let mut req = MockRequest::new(Method::Get, "/api/v1/me")
+            .header(Header::new("Authorization", format!("Bearer {}", db.jwt(&jsmith))));
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn access_token_cannot_manage_account() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let (token, hash) = auth::generate_access_token();
+
+        diesel::insert(&NewAccessToken {
+                user_id: jsmith.id,
+                name: "script",
+                token_hash: hash.as_str(),
+                scopes: "read write",
+                created_at: 0,
+            })
+            .into(access_tokens::table)
+            .execute(db.connection().deref())
+            .unwrap();
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me, super::tokens]);
+        let mut me_req = MockRequest::new(Method::Get, "/api/v1/me")
+            .header(Header::new("Authorization", format!("Bearer {}", token)));
+        let me_response = me_req.dispatch_with(&rocket);
+        let mut tokens_req = MockRequest::new(Method::Get, "/api/v1/tokens")
+            .header(Header::new("Authorization", format!("Bearer {}", token)));
+        let mut tokens_response = tokens_req.dispatch_with(&rocket);
+
+        let body = tokens_response.body().and_then(|b| b.into_string());
+
+        assert_eq!(me_response.status(), Status::Ok);
+        assert_eq!(error_message(body),
+                   Some(Error::MissingScope.description().to_owned()));
+    }
+
+    #[test]
+    fn removes_undecryptable_cookie() {
+        let mut db = TestDb::seeded();
+        db.config.cookie_secret = Some(String::from("cookie secret"));
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me]);
+        let mut req = MockRequest::new(Method::Get, "/api/v1/me")
+            .cookie(Cookie::new("jwt", "sealed.under.another.key"));
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::NotFound);
+        assert!(response.headers()
+            .get("Set-Cookie")
+            .any(|c| c.starts_with("jwt=;") && c.contains("Max-Age=0")));
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me]);
+        let mut req = MockRequest::new(Method::Get, "/api/v1/me")
+            .header(Header::new("Authorization", format!("bearer {}", db.jwt(&jsmith))));
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn access_token_of_unconfirmed_user() {
+        let db = TestDb::seeded();
+        let jdoe = db.user("jdoe");
+
+        let (token, hash) = auth::generate_access_token();
+
+        diesel::insert(&NewAccessToken {
+                user_id: jdoe.id,
+                name: "script",
+                token_hash: hash.as_str(),
+                scopes: "read",
+                created_at: 0,
+            })
+            .into(access_tokens::table)
+            .execute(db.connection().deref())
+            .unwrap();
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me]);
+        let mut req = MockRequest::new(Method::Get, "/api/v1/me")
+            .header(Header::new("Authorization", format!("Bearer {}", token)));
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn update_name() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let update = UpdateUser {
+            name: Some(String::from("Johnny Smith")),
+            email: None,
+            username: None,
+        };
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::update_me]);
+        let mut req = db.authed(Method::Patch, "/api/v1/me", &jsmith)
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&update).unwrap());
+        let response = req.dispatch_with(&rocket);
+
+        let user = db.user("jsmith");
+
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get("Set-Cookie").any(|c| c.starts_with("jwt=")));
+        assert_eq!(user.name, "Johnny Smith");
+        assert!(user.conf);
+    }
+
+    #[test]
+    fn update_email_unconfirms() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let update = UpdateUser {
+            name: None,
+            email: Some(String::from("john@website.com")),
+            username: None,
+        };
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::update_me]);
+        let mut req = db.authed(Method::Patch, "/api/v1/me", &jsmith)
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&update).unwrap());
+        let mut response = req.dispatch_with(&rocket);
+
+        let body = response.body().and_then(|b| b.into_string());
+        let user = db.user("jsmith");
+
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error_message(body),
+                   Some(Error::NotConfirmed(ThresholdKind::EmailChange).description().to_owned()));
+        assert!(response.headers()
+            .get("Set-Cookie")
+            .any(|c| c.starts_with("jwt=;") && c.contains("Max-Age=0")));
+        assert_eq!(user.email, "john@website.com");
+        assert!(!user.conf);
+    }
+
+    #[test]
+    fn session_of_unconfirmed_user() {
+        let db = TestDb::seeded();
+        let jdoe = db.user("jdoe");
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::me]);
+        let mut cookie_req = db.authed(Method::Get, "/api/v1/me", &jdoe);
+        let cookie_response = cookie_req.dispatch_with(&rocket);
+        let mut bearer_req = MockRequest::new(Method::Get, "/api/v1/me")
+            .header(Header::new("Authorization", format!("Bearer {}", db.jwt(&jdoe))));
+        let bearer_response = bearer_req.dispatch_with(&rocket);
+
+        assert_eq!(cookie_response.status(), Status::NotFound);
+        assert!(cookie_response.headers()
+            .get("Set-Cookie")
+            .any(|c| c.starts_with("jwt=;") && c.contains("Max-Age=0")));
+        assert_eq!(bearer_response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn change_password_wrong_current() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let change = ChangePassword {
+            current_password: String::from("not_test"),
+            new_password: String::from("new_pass"),
+        };
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::change_password]);
+        let mut req = db.authed(Method::Post, "/api/v1/me/password", &jsmith)
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&change).unwrap());
+        let mut response = req.dispatch_with(&rocket);
+
+        let body = response.body().and_then(|b| b.into_string());
+
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(error_message(body),
+                   Some(Error::WrongPassword.description().to_owned()));
+    }
+
+    #[test]
+    fn delete_account() {
+        let db = TestDb::seeded();
+        let jsmith = db.user("jsmith");
+
+        let delete = DeleteAccount { password: String::from(PASSWORD) };
+
+        let rocket = db.rocket().mount("/api/v1", routes![super::delete_me]);
+        let mut req = db.authed(Method::Delete, "/api/v1/me", &jsmith)
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&delete).unwrap());
+        let response = req.dispatch_with(&rocket);
+
+        let remaining: Vec<User> = users::table.load(db.connection().deref()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].username, "jdoe");
+    }
+}
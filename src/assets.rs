@@ -0,0 +1,617 @@
+//! Serves the built frontend from `STATIC_ROOT`.
+//!
+//! Every file gets a strong `ETag` and a `Last-Modified` so browsers can
+//! revalidate with a `304`. Webpack's content-hashed output never changes
+//! under the same name, so it is cached for a year. Anything else has to be
+//! revalidated each time. A `.br` or `.gz` copy next to a file is served
+//! instead of it to clients that accept that encoding.
+//!
+//! Built with the `embed-assets` feature, the frontend is compiled into the
+//! binary by `build.rs` and served from memory, so the binary can be
+//! deployed on its own.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::request;
+use rocket::outcome::Outcome;
+use rocket::Request;
+use rocket::response::{Responder, Response};
+use rocket::http::Status;
+
+use ring::digest;
+
+use time;
+
+use super::config::Config;
+
+const HTTP_DATE: &'static str = "%a, %d %b %Y %H:%M:%S GMT";
+
+const IMMUTABLE: &'static str = "public, max-age=31536000, immutable";
+const REVALIDATE: &'static str = "no-cache";
+
+// shortest hash webpack is configured to put in a file name
+const MIN_HASH_LENGTH: usize = 8;
+
+/// Precompressed copies we look for, best first, with the suffix they are
+/// saved under.
+const ENCODINGS: &'static [(&'static str, &'static str)] = &[("br", "br"), ("gzip", "gz")];
+
+const CONTENT_TYPES: &'static [(&'static str, &'static str)] =
+    &[("html", "text/html; charset=utf-8"),
+      ("css", "text/css; charset=utf-8"),
+      ("js", "application/javascript; charset=utf-8"),
+      ("json", "application/json"),
+      ("map", "application/json"),
+      ("webmanifest", "application/manifest+json"),
+      ("txt", "text/plain; charset=utf-8"),
+      ("xml", "application/xml"),
+      ("svg", "image/svg+xml"),
+      ("png", "image/png"),
+      ("jpg", "image/jpeg"),
+      ("jpeg", "image/jpeg"),
+      ("gif", "image/gif"),
+      ("webp", "image/webp"),
+      ("ico", "image/x-icon"),
+      ("woff", "font/woff"),
+      ("woff2", "font/woff2"),
+      ("ttf", "font/ttf"),
+      ("otf", "font/otf"),
+      ("eot", "application/vnd.ms-fontobject"),
+      ("wasm", "application/wasm")];
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+struct CachedEtag {
+    modified: SystemTime,
+    len: u64,
+    etag: String,
+}
+
+enum Source {
+    Disk {
+        root: PathBuf,
+        // hashing a file on every request would be wasteful, so etags are
+        // kept until the file's size or modification time changes
+        etags: Mutex<HashMap<PathBuf, CachedEtag>>,
+    },
+    Memory {
+        files: HashMap<&'static str, (&'static [u8], String)>,
+        modified: u64,
+    },
+}
+
+impl Source {
+    /// When the file at `path` last changed, in seconds since the epoch, and
+    /// its etag, or `None` if there is no such file.
+    fn find(&self, path: &Path) -> Option<(u64, String)> {
+        match *self {
+            Source::Disk { ref root, ref etags } => {
+                let path = root.join(path);
+                let metadata = match fs::metadata(&path) {
+                    Ok(ref metadata) if metadata.is_file() => metadata.clone(),
+                    _ => return None,
+                };
+                let modified = match metadata.modified() {
+                    Ok(modified) => modified,
+                    Err(_) => return None,
+                };
+                let modified_secs = modified.duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0);
+
+                let mut etags = etags.lock().unwrap();
+                if let Some(cached) = etags.get(&path) {
+                    if cached.modified == modified && cached.len == metadata.len() {
+                        return Some((modified_secs, cached.etag.clone()));
+                    }
+                }
+
+                let mut contents = Vec::new();
+                if File::open(&path).and_then(|mut file| file.read_to_end(&mut contents)).is_err() {
+                    return None;
+                }
+                let etag = etag_for(&contents);
+
+                etags.insert(path,
+                             CachedEtag {
+                                 modified: modified,
+                                 len: metadata.len(),
+                                 etag: etag.clone(),
+                             });
+                Some((modified_secs, etag))
+            }
+            Source::Memory { ref files, modified } => {
+                path.to_str()
+                    .and_then(|path| files.get(path))
+                    .map(|&(_, ref etag)| (modified, etag.clone()))
+            }
+        }
+    }
+
+    fn open(&self, path: &Path) -> Option<Box<ReadSeek>> {
+        match *self {
+            Source::Disk { ref root, .. } => {
+                File::open(root.join(path)).ok().map(|file| Box::new(file) as Box<ReadSeek>)
+            }
+            Source::Memory { ref files, .. } => {
+                path.to_str()
+                    .and_then(|path| files.get(path))
+                    .map(|&(contents, _)| Box::new(Cursor::new(contents)) as Box<ReadSeek>)
+            }
+        }
+    }
+}
+
+/// Where assets are served from, handed to Rocket as managed state.
+pub struct Assets {
+    source: Source,
+}
+
+/// The request headers that decide what an asset response looks like.
+pub struct AssetRequest {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    accept_encoding: Option<String>,
+}
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for AssetRequest {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AssetRequest, ()> {
+        let headers = request.headers();
+
+        Outcome::Success(AssetRequest {
+            if_none_match: headers.get_one("If-None-Match").map(String::from),
+            if_modified_since: headers.get_one("If-Modified-Since").map(String::from),
+            accept_encoding: headers.get_one("Accept-Encoding").map(String::from),
+        })
+    }
+}
+
+/// A file ready to be sent, or a `304` if the client's copy is current.
+pub struct Asset {
+    body: Option<Box<ReadSeek>>,
+    content_type: &'static str,
+    encoding: Option<&'static str>,
+    etag: String,
+    last_modified: String,
+    cache_control: &'static str,
+    vary: bool,
+}
+
+impl<'a> Responder<'a> for Asset {
+    fn respond(self) -> Result<Response<'a>, Status> {
+        let mut response = Response::build();
+        response.raw_header("ETag", self.etag)
+            .raw_header("Last-Modified", self.last_modified)
+            .raw_header("Cache-Control", self.cache_control);
+
+        if self.vary {
+            response.raw_header("Vary", "Accept-Encoding");
+        }
+
+        match self.body {
+            Some(body) => {
+                response.raw_header("Content-Type", self.content_type);
+                if let Some(encoding) = self.encoding {
+                    response.raw_header("Content-Encoding", encoding);
+                }
+                response.sized_body(body);
+            }
+            None => {
+                response.status(Status::NotModified);
+            }
+        }
+
+        Ok(response.finalize())
+    }
+}
+
+impl Assets {
+    /// The assets compiled in with the `embed-assets` feature, unless
+    /// `ASSETS_FROM_DISK` asks for `STATIC_ROOT` instead, as it is without the
+    /// feature.
+    pub fn new(config: &Config) -> Self {
+        if cfg!(feature = "embed-assets") && !config.assets_from_disk {
+            Assets::in_memory(EMBEDDED, EMBEDDED_AT)
+        } else {
+            Assets::with_root(&config.static_root)
+        }
+    }
+
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        Assets {
+            source: Source::Disk {
+                root: root.as_ref().to_path_buf(),
+                etags: Mutex::new(HashMap::new()),
+            },
+        }
+    }
+
+    /// Serves `files`, each a `/` separated path and its contents, as last
+    /// changed at `modified` seconds since the epoch.
+    pub fn in_memory(files: &[(&'static str, &'static [u8])], modified: u64) -> Self {
+        Assets {
+            source: Source::Memory {
+                files: files.iter()
+                    .map(|&(path, contents)| (path, (contents, etag_for(contents))))
+                    .collect(),
+                modified: modified,
+            },
+        }
+    }
+
+    /// The file at `path`, in the best encoding the client accepts, or `None`
+    /// if there is no such file.
+    pub fn serve<P: AsRef<Path>>(&self, path: P, request: &AssetRequest) -> Option<Asset> {
+        let path = path.as_ref();
+
+        // rocket already refuses `..` in paths, but nothing should get out of
+        // the root however this is called
+        let inside_root = path.components().all(|component| match component {
+            Component::Normal(_) => true,
+            _ => false,
+        });
+        if !inside_root {
+            return None;
+        }
+
+        let (modified, identity_etag) = match self.source.find(path) {
+            Some(found) => found,
+            None => return None,
+        };
+
+        let compressed: Vec<(&'static str, PathBuf, String)> = ENCODINGS.iter()
+            .filter_map(|&(encoding, suffix)| {
+                let variant = with_suffix(path, suffix);
+                self.source.find(&variant).map(|(_, etag)| (encoding, variant, etag))
+            })
+            .collect();
+        let vary = !compressed.is_empty();
+
+        let accept_encoding = request.accept_encoding.as_ref().map_or("", String::as_str);
+        let (encoding, file, etag) = match compressed.into_iter()
+            .find(|&(encoding, _, _)| accepts(accept_encoding, encoding)) {
+            Some((encoding, file, etag)) => (Some(encoding), file, etag),
+            None => (None, path.to_path_buf(), identity_etag),
+        };
+
+        let not_modified = match (request.if_none_match.as_ref(),
+                                  request.if_modified_since.as_ref()) {
+            (Some(if_none_match), _) => etag_matches(if_none_match, &etag),
+            (None, Some(since)) => parse_http_date(since).map_or(false, |since| modified <= since),
+            (None, None) => false,
+        };
+
+        let body = if not_modified {
+            None
+        } else {
+            match self.source.open(&file) {
+                Some(body) => Some(body),
+                None => return None,
+            }
+        };
+
+        Some(Asset {
+            body: body,
+            content_type: content_type(path),
+            encoding: encoding,
+            etag: etag,
+            last_modified: http_date(modified),
+            cache_control: cache_control(path),
+            vary: vary,
+        })
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// A strong etag: the start of the SHA-256 of the contents, quoted.
+fn etag_for(contents: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, contents);
+
+    let mut etag = String::from("\"");
+    for byte in &digest.as_ref()[..16] {
+        write!(etag, "{:02x}", byte).unwrap();
+    }
+    etag.push('"');
+    etag
+}
+
+/// Whether `If-None-Match` lists `etag`, using the weak comparison RFC 7232
+/// asks for.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*" ||
+    if_none_match.split(',')
+        .map(|candidate| candidate.trim().trim_left_matches("W/"))
+        .any(|candidate| candidate == etag)
+}
+
+/// Whether `Accept-Encoding` allows `encoding`, by its own entry or by `*`.
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    let mut wildcard = false;
+
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params.map(str::trim)
+            .filter(|param| param.starts_with("q="))
+            .filter_map(|param| param[2..].parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality > 0.0;
+        } else if name == "*" {
+            wildcard = quality > 0.0;
+        }
+    }
+
+    wildcard
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+
+    CONTENT_TYPES.iter()
+        .find(|&&(known, _)| known.eq_ignore_ascii_case(extension))
+        .map_or("application/octet-stream", |&(_, content_type)| content_type)
+}
+
+/// Whether webpack put a content hash in the name, as in `main-3f9a0c21.js`.
+fn is_content_hashed(path: &Path) -> bool {
+    let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(stem) => stem,
+        None => return false,
+    };
+
+    match stem.rfind('-') {
+        Some(index) => {
+            let hash = &stem[index + 1..];
+            hash.len() >= MIN_HASH_LENGTH && hash.chars().all(|c| c.is_digit(16))
+        }
+        None => false,
+    }
+}
+
+fn cache_control(path: &Path) -> &'static str {
+    if is_content_hashed(path) { IMMUTABLE } else { REVALIDATE }
+}
+
+fn http_date(secs: u64) -> String {
+    let tm = time::at_utc(time::Timespec::new(secs as i64, 0));
+    time::strftime(HTTP_DATE, &tm).unwrap()
+}
+
+fn parse_http_date(date: &str) -> Option<u64> {
+    time::strptime(date.trim(), HTTP_DATE)
+        .ok()
+        .map(|tm| tm.to_timespec().sec)
+        .and_then(|secs| if secs < 0 { None } else { Some(secs as u64) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::io::Write;
+
+    use rand::{thread_rng, Rng};
+
+    use rocket;
+    use rocket::State;
+    use rocket::testing::MockRequest;
+    use rocket::http::{Method, Header};
+
+    #[get("/<path..>")]
+    fn asset(path: PathBuf, assets: State<Assets>, request: AssetRequest) -> Option<Asset> {
+        assets.serve(path, &request)
+    }
+
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new(files: &[(&str, &str)]) -> TempRoot {
+            let mut bytes = [0u8; 8];
+            thread_rng().fill_bytes(&mut bytes);
+            let name: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let root = env::temp_dir().join(format!("pupil-assets-{}", name));
+
+            for &(path, contents) in files {
+                let path = root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+            }
+
+            TempRoot(root)
+        }
+
+        fn rocket(&self) -> rocket::Rocket {
+            rocket::ignite().manage(Assets::with_root(&self.0)).mount("/", routes![asset])
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn serves_with_validators() {
+        let root = TempRoot::new(&[("index.html", "<html></html>")]);
+        let rocket = root.rocket();
+
+        let mut req = MockRequest::new(Method::Get, "/index.html");
+        let mut response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Content-Type"),
+                   Some("text/html; charset=utf-8"));
+        assert_eq!(response.headers().get_one("Cache-Control"), Some(REVALIDATE));
+        assert_eq!(response.headers().get_one("ETag"),
+                   Some(etag_for(b"<html></html>").as_str()));
+        assert!(response.headers().get_one("Last-Modified").unwrap().ends_with(" GMT"));
+        assert_eq!(response.body().and_then(|b| b.into_string()),
+                   Some(String::from("<html></html>")));
+    }
+
+    #[test]
+    fn hashed_files_are_immutable() {
+        let root = TempRoot::new(&[("static/js/main-3f9a0c21d4e5.js", "main()")]);
+        let rocket = root.rocket();
+
+        let mut req = MockRequest::new(Method::Get, "/static/js/main-3f9a0c21d4e5.js");
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.headers().get_one("Cache-Control"), Some(IMMUTABLE));
+        assert_eq!(response.headers().get_one("Content-Type"),
+                   Some("application/javascript; charset=utf-8"));
+    }
+
+    #[test]
+    fn not_modified() {
+        let root = TempRoot::new(&[("app.css", "body {}")]);
+        let rocket = root.rocket();
+        let etag = etag_for(b"body {}");
+
+        let mut req = MockRequest::new(Method::Get, "/app.css")
+            .header(Header::new("If-None-Match", format!("\"other\", {}", etag)));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert!(response.body().is_none());
+
+        // 2100-01-01
+        let mut req = MockRequest::new(Method::Get, "/app.css")
+            .header(Header::new("If-Modified-Since", http_date(4102444800)));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::NotModified);
+
+        let mut req = MockRequest::new(Method::Get, "/app.css")
+            .header(Header::new("If-None-Match", "\"other\""));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+    }
+
+    #[test]
+    fn precompressed_variants() {
+        let root = TempRoot::new(&[("app.js", "plain"), ("app.js.br", "brotli"),
+                                   ("app.js.gz", "gzip")]);
+        let rocket = root.rocket();
+
+        let get = |accept_encoding: &str| {
+            let mut req = MockRequest::new(Method::Get, "/app.js")
+                .header(Header::new("Accept-Encoding", accept_encoding.to_string()));
+            let mut response = req.dispatch_with(&rocket);
+
+            assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+            assert_eq!(response.headers().get_one("Content-Type"),
+                       Some("application/javascript; charset=utf-8"));
+
+            let encoding = response.headers().get_one("Content-Encoding").map(String::from);
+            (encoding, response.body().and_then(|b| b.into_string()).unwrap())
+        };
+
+        assert_eq!(get("gzip, deflate, br"), (Some(String::from("br")), String::from("brotli")));
+        assert_eq!(get("gzip, br;q=0"), (Some(String::from("gzip")), String::from("gzip")));
+        assert_eq!(get("identity"), (None, String::from("plain")));
+    }
+
+    #[test]
+    fn serves_from_memory() {
+        let assets = Assets::in_memory(&[("index.html", b"<html></html>" as &'static [u8]),
+                                         ("static/app.js", b"plain" as &'static [u8]),
+                                         ("static/app.js.gz", b"gzip" as &'static [u8])],
+                                       784111777);
+        let rocket = rocket::ignite().manage(assets).mount("/", routes![asset]);
+
+        let mut req = MockRequest::new(Method::Get, "/index.html");
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.headers().get_one("Last-Modified"),
+                   Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!(response.body().and_then(|b| b.into_string()),
+                   Some(String::from("<html></html>")));
+
+        let mut req = MockRequest::new(Method::Get, "/static/app.js")
+            .header(Header::new("Accept-Encoding", "gzip"));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.body().and_then(|b| b.into_string()), Some(String::from("gzip")));
+
+        let mut req = MockRequest::new(Method::Get, "/static/app.js")
+            .header(Header::new("If-None-Match", etag_for(b"plain")));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::NotModified);
+
+        let mut req = MockRequest::new(Method::Get, "/static");
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::NotFound);
+    }
+
+    #[test]
+    fn missing_files() {
+        let root = TempRoot::new(&[("static/img/logo.png", "png")]);
+        let rocket = root.rocket();
+
+        for path in &["/nope.html", "/static/img"] {
+            let mut req = MockRequest::new(Method::Get, path);
+            assert_eq!(req.dispatch_with(&rocket).status(), Status::NotFound);
+        }
+
+        let request = AssetRequest {
+            if_none_match: None,
+            if_modified_since: None,
+            accept_encoding: None,
+        };
+        let assets = Assets::with_root(root.0.join("static"));
+        assert!(assets.serve("../static/img/logo.png", &request).is_none());
+        assert!(assets.serve("img/logo.png", &request).is_some());
+    }
+
+    #[test]
+    fn accept_encoding() {
+        assert!(accepts("gzip, deflate, br", "br"));
+        assert!(accepts("GZIP", "gzip"));
+        assert!(accepts("*", "br"));
+        assert!(!accepts("*, br;q=0", "br"));
+        assert!(!accepts("gzip;q=0", "gzip"));
+        assert!(!accepts("", "gzip"));
+    }
+
+    #[test]
+    fn content_types() {
+        assert_eq!(content_type(Path::new("a/b.svg")), "image/svg+xml");
+        assert_eq!(content_type(Path::new("font.woff2")), "font/woff2");
+        assert_eq!(content_type(Path::new("LOGO.PNG")), "image/png");
+        assert_eq!(content_type(Path::new("Makefile")), "application/octet-stream");
+    }
+
+    #[test]
+    fn content_hashes() {
+        assert!(is_content_hashed(Path::new("static/js/main-3f9a0c21d4e5b6a7c8d9.js")));
+        assert!(is_content_hashed(Path::new("main-3F9A0C21.css")));
+        assert!(!is_content_hashed(Path::new("index.html")));
+        assert!(!is_content_hashed(Path::new("font-awesome.css")));
+        assert!(!is_content_hashed(Path::new("main-3f9a.js")));
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+}
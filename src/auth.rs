@@ -0,0 +1,346 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use rocket::request;
+use rocket::outcome::Outcome;
+use rocket::Request;
+use rocket::State;
+use rocket::http::Status;
+
+use rand::{thread_rng, Rng};
+
+use ring::digest;
+
+use log::LogLevel;
+
+use super::model::{SafeUser, UserToken};
+use super::error::{Error, ThresholdKind};
+use super::csrf;
+use super::store::Store;
+use super::config::Config;
+use super::keys::JwtKeys;
+use super::session::AuthCookie;
+use super::logging;
+
+const ACCESS_TOKEN_PREFIX: &'static str = "pupil_";
+const ACCESS_TOKEN_LENGTH: usize = 32;
+
+/// How the current request proved who it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMethod {
+    Cookie,
+    Bearer,
+    AccessToken,
+}
+
+/// What an authenticated request is allowed to do. Sessions get every scope,
+/// personal access tokens only the ones they were created with, and never
+/// `Account`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Account,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Account => "account",
+        }
+    }
+
+    pub fn join(scopes: &[Scope]) -> String {
+        scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(" ")
+    }
+
+    pub fn split(scopes: &str) -> Vec<Scope> {
+        scopes.split_whitespace().filter_map(|s| s.parse().ok()).collect()
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Scope, ()> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "account" => Ok(Scope::Account),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Auth {
+    pub user: SafeUser,
+    pub method: AuthMethod,
+    pub scopes: Vec<Scope>,
+}
+
+impl Auth {
+    pub fn require(&self, scope: Scope) -> Result<(), Error> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(Error::MissingScope)
+        }
+    }
+
+    fn session(token: UserToken, method: AuthMethod) -> Self {
+        Auth {
+            user: SafeUser::from(token),
+            method: method,
+            scopes: vec![Scope::Read, Scope::Write, Scope::Account],
+        }
+    }
+}
+
+fn decode_jwt(token: &str, keys: &JwtKeys, leeway: i64) -> Option<UserToken> {
+    keys.decode::<UserToken>(token, UserToken::validation(leeway)).ok()
+}
+
+fn authenticate_access_token(request: &Request, token: &str) -> Result<Auth, Error> {
+    let store = match request.guard::<State<Store>>() {
+        Outcome::Success(store) => store,
+        _ => return Err(Error::BadToken),
+    };
+
+    match store.access_tokens().authenticate(&hash_access_token(token))? {
+        // the same rule logging in with a password enforces
+        Some((_, ref user)) if !user.conf => Err(Error::NotConfirmed(ThresholdKind::Login)),
+        Some((access_token, user)) => {
+            Ok(Auth {
+                user: SafeUser::from(user),
+                method: AuthMethod::AccessToken,
+                scopes: Scope::split(&access_token.scopes),
+            })
+        }
+        None => Err(Error::BadToken),
+    }
+}
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for Auth {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Auth, Error> {
+        let config = match request.guard::<State<Config>>() {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Failure((Status::InternalServerError, Error::BadCookie)),
+        };
+        let keys = match request.guard::<State<JwtKeys>>() {
+            Outcome::Success(keys) => keys,
+            _ => return Outcome::Failure((Status::InternalServerError, Error::BadCookie)),
+        };
+
+        if let Some(header) = request.headers().get_one("Authorization") {
+            let mut parts = header.trim().splitn(2, ' ');
+            let token = match (parts.next(), parts.next()) {
+                (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+                    token.trim()
+                }
+                _ => return Outcome::Failure((Status::Unauthorized, Error::BadToken)),
+            };
+
+            return if token.starts_with(ACCESS_TOKEN_PREFIX) {
+                match authenticate_access_token(request, token) {
+                    Ok(auth) => Outcome::Success(auth),
+                    Err(err) => {
+                        logging::event(LogLevel::Info,
+                                       "auth",
+                                       &[("outcome", "bad_access_token")]);
+                        Outcome::Failure((Status::Unauthorized, err))
+                    }
+                }
+            } else {
+                match decode_jwt(token, &keys, config.jwt_leeway) {
+                    Some(ref claims) if !claims.conf => {
+                        logging::event(LogLevel::Info, "auth", &[("outcome", "not_confirmed")]);
+                        Outcome::Failure((Status::Unauthorized,
+                                          Error::NotConfirmed(ThresholdKind::Login)))
+                    }
+                    Some(claims) => Outcome::Success(Auth::session(claims, AuthMethod::Bearer)),
+                    None => {
+                        logging::event(LogLevel::Info, "auth", &[("outcome", "bad_bearer")]);
+                        Outcome::Failure((Status::Unauthorized, Error::BadToken))
+                    }
+                }
+            };
+        }
+
+        let mut cookies = request.cookies();
+        let auth_cookie = AuthCookie::new(&config);
+
+        let jwt = match auth_cookie.jwt(&mut cookies) {
+            Some(jwt) => jwt,
+            None => return Outcome::Failure((Status::NotFound, Error::BadCookie)),
+        };
+
+        // the cookie is sent by the browser on its own, so state changes made
+        // with it have to come from our own pages
+        if let Err(err) = csrf::check(request) {
+            logging::event(LogLevel::Warn, "auth", &[("outcome", "cross_origin")]);
+            return Outcome::Failure((Status::Forbidden, err));
+        }
+
+        match decode_jwt(&jwt, &keys, config.jwt_leeway) {
+            // sessions are only handed out to confirmed users, but one can
+            // outlive the confirmation, e.g. across an email change
+            Some(ref claims) if !claims.conf => {
+                logging::event(LogLevel::Info, "auth", &[("outcome", "not_confirmed")]);
+                auth_cookie.remove(&mut cookies);
+                Outcome::Failure((Status::NotFound, Error::NotConfirmed(ThresholdKind::Login)))
+            }
+            Some(claims) => Outcome::Success(Auth::session(claims, AuthMethod::Cookie)),
+            None => {
+                logging::event(LogLevel::Info, "auth", &[("outcome", "bad_cookie")]);
+                auth_cookie.remove(&mut cookies);
+                Outcome::Failure((Status::NotFound, Error::BadCookie))
+            }
+        }
+    }
+}
+
+/// Creates a new personal access token, returning the token itself (shown to
+/// the user once) and the hash that gets stored.
+pub fn generate_access_token() -> (String, String) {
+    let mut bytes = [0u8; ACCESS_TOKEN_LENGTH];
+    thread_rng().fill_bytes(&mut bytes);
+
+    let mut token = String::from(ACCESS_TOKEN_PREFIX);
+    for byte in bytes.iter() {
+        write!(token, "{:02x}", byte).unwrap();
+    }
+
+    let hash = hash_access_token(&token);
+    (token, hash)
+}
+
+/// Access tokens are long and random, so a plain SHA-256 is enough to keep
+/// them out of the database while still allowing lookup by hash.
+pub fn hash_access_token(token: &str) -> String {
+    let digest = digest::digest(&digest::SHA256, token.as_bytes());
+
+    let mut hash = String::with_capacity(64);
+    for byte in digest.as_ref() {
+        write!(hash, "{:02x}", byte).unwrap();
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use jwt::{encode, Algorithm, Header};
+
+    use super::super::model::User;
+
+    fn claims() -> UserToken {
+        UserToken::new(User {
+            id: 1,
+            public_id: String::from("8c6a1e0f4b2d9735e1c0a4f6d8b2e913"),
+            name: String::from("John Smith"),
+            email: String::from("jsmith@website.com"),
+            username: String::from("jsmith"),
+            pass: String::from("hashed_password"),
+            conf: true,
+            role: String::from("student"),
+        })
+    }
+
+    fn accepted(claims: &UserToken, leeway: i64) -> bool {
+        let keys = JwtKeys::shared("secret");
+        let token = claims.construct_jwt(&keys).unwrap();
+
+        decode_jwt(&token, &keys, leeway).is_some()
+    }
+
+    #[test]
+    fn fresh_token() {
+        let keys = JwtKeys::shared("secret");
+        let token = claims().construct_jwt(&keys).unwrap();
+
+        assert_eq!(decode_jwt(&token, &keys, 0).map(|claims| claims.sub),
+                   Some(String::from("8c6a1e0f4b2d9735e1c0a4f6d8b2e913")));
+    }
+
+    #[test]
+    fn expired_token() {
+        let mut expired = claims();
+        expired.iat -= 2 * 60;
+        expired.nbf -= 2 * 60;
+        expired.exp -= 2 * 60;
+
+        assert!(!accepted(&expired, 0));
+        assert!(accepted(&expired, 90));
+    }
+
+    #[test]
+    fn token_not_yet_valid() {
+        let mut early = claims();
+        early.nbf += 60;
+
+        assert!(!accepted(&early, 0));
+        assert!(accepted(&early, 90));
+    }
+
+    #[test]
+    fn wrong_issuer_or_audience() {
+        let mut issuer = claims();
+        issuer.iss = String::from("someone-else");
+        assert!(!accepted(&issuer, 0));
+
+        let mut audience = claims();
+        audience.aud = String::from("someone-else");
+        assert!(!accepted(&audience, 0));
+    }
+
+    #[test]
+    fn wrong_algorithm() {
+        let keys = JwtKeys::shared("secret");
+        let token = encode(&Header::new(Algorithm::HS512), &claims(), b"secret").unwrap();
+
+        assert!(decode_jwt(&token, &keys, 0).is_none());
+    }
+
+    #[test]
+    fn tampered_token() {
+        let keys = JwtKeys::shared("secret");
+        let token = claims().construct_jwt(&keys).unwrap();
+
+        let mut other = claims();
+        other.sub = String::from("0f1e2d3c4b5a69788796a5b4c3d2e1f0");
+        let forged = other.construct_jwt(&JwtKeys::shared("another secret")).unwrap();
+
+        // the other token's claims under this one's signature
+        let signature = token.rsplitn(2, '.').next().unwrap();
+        let claims = forged.rsplitn(2, '.').nth(1).unwrap();
+        let tampered = format!("{}.{}", claims, signature);
+
+        assert!(decode_jwt(&tampered, &keys, 0).is_none());
+        assert!(decode_jwt(&forged, &keys, 0).is_none());
+    }
+
+    #[test]
+    fn access_token_hash() {
+        let (token, hash) = generate_access_token();
+
+        assert!(token.starts_with(ACCESS_TOKEN_PREFIX));
+        assert_eq!(token.len(), ACCESS_TOKEN_PREFIX.len() + 2 * ACCESS_TOKEN_LENGTH);
+        assert_eq!(hash, hash_access_token(&token));
+        assert!(hash != hash_access_token("pupil_notthesametoken"));
+    }
+
+    #[test]
+    fn scopes_roundtrip() {
+        let scopes = vec![Scope::Read, Scope::Write];
+
+        assert_eq!(Scope::join(&scopes), "read write");
+        assert_eq!(Scope::split("read write bogus"), scopes);
+    }
+}
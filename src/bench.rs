@@ -0,0 +1,107 @@
+//! Login throughput and latency with argon2 on the calling threads versus on
+//! the hasher. These take a while, so they are only built with the `bench`
+//! feature:
+//!
+//! ```sh
+//! cargo test --release --features bench bench_login -- --nocapture
+//! ```
+
+use std::f64;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use super::error::Error;
+use super::hasher::Hasher;
+use super::metrics::seconds;
+use super::passwd;
+
+// roughly rocket's default worker count on a small machine
+const CLIENTS: usize = 16;
+const LOGINS_PER_CLIENT: usize = 8;
+
+/// NaN when there is nothing to rank, as when every attempt was shed.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+
+    let index = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[index]
+}
+
+fn report(name: &str, elapsed: f64, mut latencies: Vec<f64>, rejected: usize) {
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    println!("{}: {} logins in {:.2}s ({:.1}/s), {} rejected, \
+              p50 {:.0}ms p95 {:.0}ms p99 {:.0}ms",
+             name,
+             latencies.len(),
+             elapsed,
+             latencies.len() as f64 / elapsed,
+             rejected,
+             percentile(&latencies, 0.50) * 1000.0,
+             percentile(&latencies, 0.95) * 1000.0,
+             percentile(&latencies, 0.99) * 1000.0);
+}
+
+/// Runs `login` from every client at once, returning each latency and how
+/// many attempts were shed.
+fn run<F>(login: F) -> (f64, Vec<f64>, usize)
+    where F: Fn() -> Result<bool, Error> + Send + Sync + 'static
+{
+    let login = Arc::new(login);
+    let started = Instant::now();
+
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let login = login.clone();
+            thread::spawn(move || {
+                let mut latencies = Vec::new();
+                let mut rejected = 0;
+                for _ in 0..LOGINS_PER_CLIENT {
+                    let attempt = Instant::now();
+                    match login() {
+                        Ok(valid) => {
+                            assert!(valid);
+                            latencies.push(seconds(attempt.elapsed()));
+                        }
+                        Err(_) => rejected += 1,
+                    }
+                }
+                (latencies, rejected)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::new();
+    let mut rejected = 0;
+    for client in clients {
+        let (client_latencies, client_rejected) = client.join().unwrap();
+        latencies.extend(client_latencies);
+        rejected += client_rejected;
+    }
+
+    (seconds(started.elapsed()), latencies, rejected)
+}
+
+#[test]
+fn bench_login() {
+    let hashed = passwd::hash_password("jsmith", "password", "secret");
+
+    let direct = hashed.clone();
+    let (elapsed, latencies, rejected) =
+        run(move || Ok(passwd::verify_password(&direct, "password")));
+    report("direct", elapsed, latencies, rejected);
+
+    for &(workers, depth) in &[(2, 64), (4, 64), (4, 8)] {
+        let hasher = Hasher::with_capacity(workers, depth);
+        let pooled = hashed.clone();
+        let (elapsed, latencies, rejected) =
+            run(move || hasher.verify_password(&pooled, "password"));
+        report(&format!("hasher ({} workers, queue {})", workers, depth),
+               elapsed,
+               latencies,
+               rejected);
+    }
+}
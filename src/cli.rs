@@ -0,0 +1,432 @@
+use std::io::{self, BufRead};
+use std::ops::Deref;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use diesel::pg::PgConnection;
+use diesel::sqlite::SqliteConnection;
+
+use super::config::{Config, REQUIRED};
+use super::database::{Backend, ConnectionPool, Database};
+use super::error::Error;
+use super::keys::JwtKeys;
+use super::model::{User, NewUser, UserChanges, UserToken, Role, new_public_id};
+use super::passwd;
+use super::migrations::{self, MigrationConnection};
+use super::store::Store;
+
+const MIN_SECRET_LENGTH: usize = 32;
+const DATABASE_ONLY: &'static [&'static str] = &["DATABASE_URL"];
+const HASHING: &'static [&'static str] = &["DATABASE_URL", "HASH_SECRET"];
+const SIGNING: &'static [&'static str] = &["DATABASE_URL", "JWT_SECRET"];
+const SECRETS: &'static [&'static str] = &["JWT_SECRET", "HASH_SECRET"];
+
+pub fn app() -> App<'static, 'static> {
+    App::new("pupil-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Runs the Pupil web server, or manages its database with a subcommand.")
+        .arg(Arg::with_name("demo")
+            .long("demo")
+            .help("Runs without a database, keeping everything in memory"))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Applies, reverts or lists the embedded database migrations")
+            .arg(Arg::with_name("action")
+                .possible_values(&["up", "down", "status", "redo"])
+                .default_value("up")))
+        .subcommand(SubCommand::with_name("user")
+            .about("Manages user accounts")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("create")
+                .about("Creates a user, reading the password from stdin")
+                .arg(Arg::with_name("name").required(true))
+                .arg(Arg::with_name("email").required(true))
+                .arg(Arg::with_name("username").required(true))
+                .arg(Arg::with_name("role")
+                    .long("role")
+                    .takes_value(true)
+                    .possible_values(&["student", "tutor", "admin"]))
+                .arg(Arg::with_name("confirmed")
+                    .long("confirmed")
+                    .help("Skips email confirmation")))
+            .subcommand(SubCommand::with_name("confirm")
+                .about("Marks a user's email address as confirmed")
+                .arg(Arg::with_name("username").required(true)))
+            .subcommand(SubCommand::with_name("set-password")
+                .about("Sets a user's password, reading it from stdin")
+                .arg(Arg::with_name("username").required(true)))
+            .subcommand(SubCommand::with_name("set-role")
+                .about("Changes a user's role")
+                .arg(Arg::with_name("username").required(true))
+                .arg(Arg::with_name("role")
+                    .required(true)
+                    .possible_values(&["student", "tutor", "admin"])))
+            .subcommand(SubCommand::with_name("list")
+                .about("Lists users")
+                .arg(Arg::with_name("unconfirmed")
+                    .long("unconfirmed")
+                    .help("Only lists users who have not confirmed their email"))))
+        .subcommand(SubCommand::with_name("token")
+            .about("Debugging helpers for auth tokens")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("mint")
+                .about("Prints a signed jwt for a user, as login would set it")
+                .arg(Arg::with_name("username").required(true))))
+        .subcommand(SubCommand::with_name("secrets")
+            .about("Checks the configured secrets")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("check")
+                .about("Checks that secrets are strong and work for hashing and signing")))
+}
+
+fn connect(config: &Config) -> Result<Store, String> {
+    Store::connect(config).map_err(|err| format!("could not connect to the database: {}", err))
+}
+
+// the database's own error says more on the command line than the message a
+// browser would be shown
+fn describe(err: Error) -> String {
+    match err {
+        Error::DatabaseError(err) => err.to_string(),
+        Error::PoolError(err) => format!("could not connect to the database: {}", err),
+        Error::TokenError(err) => format!("could not sign a token: {}", err),
+        err => err.to_string(),
+    }
+}
+
+fn find_user(store: &Store, username: &str) -> Result<User, String> {
+    store.users().find_by_username(username).map_err(|err| match err {
+        Error::BadUserOrPass => format!("no user named {}", username),
+        err => describe(err),
+    })
+}
+
+// never taken as an argument, where it would end up in shell history and in
+// the process list
+fn password() -> Result<String, String> {
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|err| format!("could not read password: {}", err))?;
+
+    let password = line.trim_right_matches(|c| c == '\r' || c == '\n');
+    if password.is_empty() {
+        Err(String::from("password must not be empty"))
+    } else {
+        Ok(String::from(password))
+    }
+}
+
+fn user(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let store = connect(config)?;
+
+    match matches.subcommand() {
+        ("create", Some(matches)) => {
+            let username = matches.value_of("username").unwrap();
+            let password = password()?;
+            let secure_pass = passwd::hash_password(username,
+                                                    password.as_str(),
+                                                    config.hash_secret.as_str());
+
+            let new_user = NewUser {
+                public_id: new_public_id(),
+                name: matches.value_of("name").unwrap(),
+                email: matches.value_of("email").unwrap(),
+                username: username,
+                pass: secure_pass.as_str(),
+                conf: matches.is_present("confirmed"),
+                role: matches.value_of("role").unwrap_or(Role::Student.as_str()),
+            };
+
+            let created = store.users().create(&new_user).map_err(describe)?;
+
+            println!("Created {} (id {})", created.username, created.public_id);
+        }
+        ("confirm", Some(matches)) => {
+            let user = find_user(&store, matches.value_of("username").unwrap())?;
+
+            let changes = UserChanges {
+                name: None,
+                email: None,
+                username: None,
+                conf: Some(true),
+            };
+            store.users().update(user.id, &changes).map_err(describe)?;
+
+            println!("Confirmed {}", user.username);
+        }
+        ("set-password", Some(matches)) => {
+            let user = find_user(&store, matches.value_of("username").unwrap())?;
+            let password = password()?;
+            let secure_pass = passwd::hash_password(user.username.as_str(),
+                                                    password.as_str(),
+                                                    config.hash_secret.as_str());
+
+            store.users().set_password(user.id, secure_pass.as_str()).map_err(describe)?;
+
+            println!("Set password for {}", user.username);
+        }
+        ("set-role", Some(matches)) => {
+            let user = find_user(&store, matches.value_of("username").unwrap())?;
+            let role = matches.value_of("role").unwrap();
+
+            store.users().set_role(user.id, role).map_err(describe)?;
+
+            println!("{} is now a {}", user.username, role);
+        }
+        ("list", Some(matches)) => {
+            let found = store.users()
+                .list(matches.is_present("unconfirmed"))
+                .map_err(describe)?;
+
+            for user in found {
+                println!("{:>6}  {:<20} {:<8} {:<5} {}",
+                         user.id,
+                         user.username,
+                         user.role,
+                         if user.conf { "yes" } else { "no" },
+                         user.email);
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn token(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        ("mint", Some(matches)) => {
+            let keys = JwtKeys::new(config)?;
+            let store = connect(config)?;
+            let user = find_user(&store, matches.value_of("username").unwrap())?;
+
+            println!("{}", UserToken::new(user).construct_jwt(&keys).map_err(describe)?);
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Looks for anything that would make the secrets easy to guess or unusable,
+/// returning one line per problem.
+fn check_secrets(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for &(name, secret) in &[("JWT_SECRET", &config.jwt_secret),
+                             ("HASH_SECRET", &config.hash_secret)] {
+        if secret.len() < MIN_SECRET_LENGTH {
+            problems.push(format!("{} is shorter than {} characters", name, MIN_SECRET_LENGTH));
+        }
+    }
+
+    if config.jwt_secret == config.hash_secret {
+        problems.push(String::from("JWT_SECRET and HASH_SECRET are the same"));
+    }
+
+    let hashed = passwd::hash_password("secrets-check", "password", &config.hash_secret);
+    if !passwd::verify_password(&hashed, "password") {
+        problems.push(String::from("HASH_SECRET could not hash and verify a password"));
+    }
+
+    let keys = match JwtKeys::new(config) {
+        Ok(keys) => keys,
+        Err(problem) => {
+            problems.push(problem);
+            return problems;
+        }
+    };
+
+    let token = UserToken::new(User {
+            id: 0,
+            public_id: new_public_id(),
+            name: String::from("Secrets Check"),
+            email: String::from("secrets-check@localhost"),
+            username: String::from("secrets-check"),
+            pass: String::new(),
+            conf: true,
+            role: String::from(Role::Student.as_str()),
+        })
+        .construct_jwt(&keys);
+    let verified = token.ok().map_or(false, |token| {
+        let validation = UserToken::validation(config.jwt_leeway);
+        keys.decode::<UserToken>(&token, validation).is_ok()
+    });
+    if !verified {
+        problems.push(String::from("JWT keys could not sign and verify a token"));
+    }
+
+    problems
+}
+
+fn secrets(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        ("check", Some(_)) => {
+            let problems = check_secrets(config);
+            if problems.is_empty() {
+                println!("Secrets look good.");
+                Ok(())
+            } else {
+                Err(problems.join("\n"))
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+pub fn migrate(config: &Config, action: &str) -> Result<(), String> {
+    match Backend::of(&config.database_url) {
+        Some(Backend::Sqlite) => migrate_with::<SqliteConnection>(config, action),
+        _ => migrate_with::<PgConnection>(config, action),
+    }
+}
+
+fn migrate_with<C>(config: &Config, action: &str) -> Result<(), String>
+    where C: Database + MigrationConnection
+{
+    let pool = ConnectionPool::<C>::new(config)
+        .map_err(|err| format!("could not connect to the database: {}", err))?;
+    let connection = pool.get()
+        .map_err(|err| format!("could not connect to the database: {}", err))?;
+    let connection = connection.deref();
+
+    match action {
+        "up" => {
+            let ran = migrations::run_pending(connection).map_err(|err| err.to_string())?;
+            if ran.is_empty() {
+                println!("Database is up to date.");
+            }
+            for version in ran {
+                println!("Applied {}", version);
+            }
+        }
+        "down" => {
+            match migrations::revert_latest(connection).map_err(|err| err.to_string())? {
+                Some(version) => println!("Reverted {}", version),
+                None => println!("Nothing to revert."),
+            }
+        }
+        "redo" => {
+            match migrations::redo(connection).map_err(|err| err.to_string())? {
+                Some(version) => println!("Redid {}", version),
+                None => println!("Nothing to redo."),
+            }
+        }
+        "status" => {
+            for (migration, applied) in migrations::status(connection)
+                .map_err(|err| err.to_string())? {
+                println!("[{}] {}", if applied { "X" } else { " " }, migration.version);
+            }
+        }
+        _ => return Err(format!("unknown migrate action: {}", action)),
+    }
+
+    Ok(())
+}
+
+/// The settings the subcommand that was asked for can't do without, or all
+/// of them for the server.
+pub fn required_settings(matches: &ArgMatches) -> &'static [&'static str] {
+    match matches.subcommand() {
+        ("migrate", Some(_)) => DATABASE_ONLY,
+        ("user", Some(matches)) => {
+            match matches.subcommand_name() {
+                Some("create") | Some("set-password") => HASHING,
+                _ => DATABASE_ONLY,
+            }
+        }
+        ("token", Some(_)) => SIGNING,
+        ("secrets", Some(_)) => SECRETS,
+        _ => REQUIRED,
+    }
+}
+
+/// Runs the subcommand that was asked for, or returns `None` if the server
+/// should just launch.
+pub fn run(matches: &ArgMatches, config: &Config) -> Option<Result<(), String>> {
+    match matches.subcommand() {
+        ("migrate", Some(matches)) => {
+            Some(migrate(config, matches.value_of("action").unwrap_or("up")))
+        }
+        ("user", Some(matches)) => Some(user(config, matches)),
+        ("token", Some(matches)) => Some(token(config, matches)),
+        ("secrets", Some(matches)) => Some(secrets(config, matches)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::logging;
+
+    use log::LogLevelFilter;
+
+    fn config(jwt_secret: &str, hash_secret: &str) -> Config {
+        Config {
+            profile: String::from("test"),
+            database_url: String::from("postgres://localhost/pupil"),
+            database_replica_url: None,
+            pool_size: 1,
+            pool_min_idle: None,
+            pool_connection_timeout: 1,
+            pool_max_lifetime: None,
+            pool_connect_retries: 0,
+            jwt_secret: String::from(jwt_secret),
+            hash_secret: String::from(hash_secret),
+            run_migrations: false,
+            log_level: LogLevelFilter::Off,
+            log_format: logging::Format::Logfmt,
+            hash_workers: 1,
+            hash_queue_depth: 1,
+            static_root: String::from("static"),
+            assets_from_disk: false,
+            content_security_policy: String::new(),
+            csp_report_only: false,
+            hsts_max_age: 0,
+            referrer_policy: String::new(),
+            permissions_policy: String::new(),
+            cookie_secure: false,
+            cookie_secret: None,
+            cors_origins: Vec::new(),
+            cors_methods: String::new(),
+            cors_headers: String::new(),
+            cors_credentials: false,
+            cors_max_age: 0,
+            jwt_algorithm: None,
+            jwt_private_key: None,
+            jwt_public_keys: None,
+            jwt_leeway: 0,
+            metrics_token: None,
+        }
+    }
+
+    #[test]
+    fn migrate_needs_no_secrets() {
+        let matches = app().get_matches_from(vec!["pupil-server", "migrate", "status"]);
+
+        assert_eq!(required_settings(&matches), DATABASE_ONLY);
+    }
+
+    #[test]
+    fn server_needs_everything() {
+        let matches = app().get_matches_from(vec!["pupil-server"]);
+
+        assert_eq!(required_settings(&matches), REQUIRED);
+    }
+
+    #[test]
+    fn strong_secrets() {
+        let config = config("3a8d1f0c5e7b9a2d4f6c8e0b1a3d5f7c", "9e7c5a3f1d0b8e6c4a2f0d9b7e5c3a1f");
+
+        assert!(check_secrets(&config).is_empty());
+    }
+
+    #[test]
+    fn weak_secrets() {
+        let problems = check_secrets(&config("secret", "secret"));
+
+        assert_eq!(problems.len(), 3);
+    }
+}
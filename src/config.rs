@@ -0,0 +1,770 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use dotenv::dotenv;
+
+use toml;
+
+use rand::{thread_rng, Rng};
+
+use log::LogLevelFilter;
+
+use super::database::{self, Backend};
+use super::logging;
+
+const DEFAULT_CONFIG_FILE: &'static str = "Pupil.toml";
+const DEFAULT_PROFILE: &'static str = "development";
+const DEFAULT_POOL_SIZE: usize = 10;
+const DEFAULT_POOL_CONNECTION_TIMEOUT: usize = 30;
+const DEFAULT_POOL_MAX_LIFETIME: usize = 30 * 60;
+const DEFAULT_POOL_CONNECT_RETRIES: usize = 5;
+const DEFAULT_HASH_WORKERS: usize = 2;
+const DEFAULT_HASH_QUEUE_DEPTH: usize = 32;
+const DEFAULT_STATIC_ROOT: &'static str = "static";
+const DEFAULT_CONTENT_SECURITY_POLICY: &'static str = "default-src 'self'; img-src 'self' data:; \
+                                                       object-src 'none'; base-uri 'self'; \
+                                                       form-action 'self'; frame-ancestors 'none'";
+const DEFAULT_HSTS_MAX_AGE: usize = 365 * 24 * 60 * 60;
+const DEFAULT_REFERRER_POLICY: &'static str = "strict-origin-when-cross-origin";
+const DEFAULT_PERMISSIONS_POLICY: &'static str = "camera=(), geolocation=(), microphone=()";
+const DEFAULT_CORS_METHODS: &'static str = "GET, POST, PATCH, DELETE";
+const DEFAULT_CORS_HEADERS: &'static str = "Authorization, Content-Type";
+const DEFAULT_CORS_MAX_AGE: usize = 10 * 60;
+const DEFAULT_JWT_LEEWAY: usize = 30;
+
+/// The settings without a default, all of which the server needs.
+pub const REQUIRED: &'static [&'static str] = &["DATABASE_URL", "JWT_SECRET", "HASH_SECRET"];
+
+/// Settings read once at startup and handed to Rocket as managed state.
+///
+/// Every setting is looked up, in order, as an environment variable (or
+/// `.env` entry), as a `<NAME>_FILE` variable pointing at a file holding the
+/// value, under the active profile of the TOML config file, and finally under
+/// its `[default]` table. The profile is picked with `PUPIL_ENV` and the file
+/// with `PUPIL_CONFIG`. A variable that is set to nothing counts as unset.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub profile: String,
+    pub database_url: String,
+    pub database_replica_url: Option<String>,
+    pub pool_size: u32,
+    pub pool_min_idle: Option<u32>,
+    pub pool_connection_timeout: u64,
+    pub pool_max_lifetime: Option<u64>,
+    pub pool_connect_retries: u32,
+    pub jwt_secret: String,
+    pub hash_secret: String,
+    pub run_migrations: bool,
+    pub log_level: LogLevelFilter,
+    pub log_format: logging::Format,
+    pub hash_workers: usize,
+    pub hash_queue_depth: usize,
+    pub static_root: String,
+    pub assets_from_disk: bool,
+    pub content_security_policy: String,
+    pub csp_report_only: bool,
+    pub hsts_max_age: u64,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub cookie_secure: bool,
+    pub cookie_secret: Option<String>,
+    pub cors_origins: Vec<String>,
+    pub cors_methods: String,
+    pub cors_headers: String,
+    pub cors_credentials: bool,
+    pub cors_max_age: u64,
+    pub jwt_algorithm: Option<String>,
+    pub jwt_private_key: Option<String>,
+    pub jwt_public_keys: Option<String>,
+    pub jwt_leeway: i64,
+    pub metrics_token: Option<String>,
+}
+
+/// Everything that was missing or wrong, so it can all be fixed at once.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+struct Sources {
+    vars: HashMap<String, String>,
+    file: Option<toml::Value>,
+    profile: String,
+    problems: Vec<String>,
+}
+
+impl Sources {
+    /// An environment variable that is set but empty counts as not set, the
+    /// way `FOO=` in a compose file or unit usually means "leave the default".
+    fn var(&self, name: &str) -> Option<String> {
+        self.vars.get(name).and_then(|value| if value.is_empty() {
+            None
+        } else {
+            Some(value.clone())
+        })
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<String> {
+        if let Some(value) = self.var(name) {
+            return Some(value);
+        }
+
+        if let Some(path) = self.var(&format!("{}_FILE", name)) {
+            return match read_file(&path) {
+                Ok(value) => Some(value.trim().to_owned()),
+                Err(err) => {
+                    self.problems.push(format!("{}_FILE: could not read {}: {}", name, path, err));
+                    None
+                }
+            };
+        }
+
+        let key = name.to_lowercase();
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return None,
+        };
+
+        for table in &[self.profile.as_str(), "default"] {
+            if let Some(value) = file.get(*table).and_then(|t| t.get(&key)) {
+                let value = match *value {
+                    toml::Value::String(ref value) => Some(value.clone()),
+                    toml::Value::Boolean(value) => Some(value.to_string()),
+                    toml::Value::Integer(value) => Some(value.to_string()),
+                    _ => None,
+                };
+
+                return match value {
+                    Some(value) => Some(value),
+                    None => {
+                        self.problems
+                            .push(format!("{}: [{}] {} must be a plain value", name, table, key));
+                        None
+                    }
+                };
+            }
+        }
+
+        None
+    }
+
+    fn flag(&mut self, name: &str, default: bool) -> bool {
+        match self.lookup(name) {
+            Some(value) => {
+                match value.as_str() {
+                    "true" | "1" | "yes" => true,
+                    "false" | "0" | "no" => false,
+                    _ => {
+                        self.problems.push(format!("{} must be true or false", name));
+                        default
+                    }
+                }
+            }
+            None => default,
+        }
+    }
+
+    fn optional_number(&mut self, name: &str) -> Option<usize> {
+        match self.lookup(name) {
+            Some(value) => {
+                match value.parse() {
+                    Ok(number) => Some(number),
+                    Err(_) => {
+                        self.problems
+                            .push(format!("{} must be a whole number, not {}", name, value));
+                        None
+                    }
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn number(&mut self, name: &str, default: usize) -> usize {
+        self.optional_number(name).unwrap_or(default)
+    }
+
+    fn required_if(&mut self, name: &str, required: bool) -> String {
+        if required {
+            self.required(name)
+        } else {
+            self.lookup(name).unwrap_or_default()
+        }
+    }
+
+    fn required(&mut self, name: &str) -> String {
+        match self.lookup(name) {
+            Some(ref value) if !value.is_empty() => value.clone(),
+            _ => {
+                self.problems.push(format!("{} is not set", name));
+                String::new()
+            }
+        }
+    }
+}
+
+fn random_secret() -> String {
+    let mut bytes = [0u8; 32];
+    thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_file(path: &str) -> Result<String, ::std::io::Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+impl Config {
+    /// Reads `.env`, the environment and the config file, failing with every
+    /// problem found rather than just the first.
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_for(REQUIRED, false)
+    }
+
+    /// Like `load`, only insisting on the settings in `required`, for
+    /// commands that don't use all of `REQUIRED`. Anything else left unset is
+    /// empty.
+    pub fn load_requiring(required: &[&str]) -> Result<Config, ConfigError> {
+        Config::load_for(required, false)
+    }
+
+    /// Like `load`, for the in-memory demo. No database is needed, and any
+    /// secret that isn't set is made up, since nothing outlives the process.
+    pub fn load_demo() -> Result<Config, ConfigError> {
+        Config::load_for(&[], true)
+    }
+
+    fn load_for(required: &[&str], demo: bool) -> Result<Config, ConfigError> {
+        dotenv().ok();
+
+        Config::load_from(env::vars().collect(), required, demo)
+    }
+
+    fn load_from(vars: HashMap<String, String>,
+                 required: &[&str],
+                 demo: bool)
+                 -> Result<Config, ConfigError> {
+        let path = vars.get("PUPIL_CONFIG")
+            .and_then(|path| if path.is_empty() { None } else { Some(path.clone()) });
+        let file = match path {
+            Some(ref path) => Some(read_file(path).map_err(|err| format!("{}: {}", path, err))),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Some(read_file(DEFAULT_CONFIG_FILE)
+                    .map_err(|err| format!("{}: {}", DEFAULT_CONFIG_FILE, err)))
+            }
+            None => None,
+        };
+
+        // without the file everything else is still checked, so all of it
+        // can be fixed in one go
+        let (file, problems) = match file {
+            Some(Ok(contents)) => (Some(contents), Vec::new()),
+            Some(Err(problem)) => (None, vec![problem]),
+            None => (None, Vec::new()),
+        };
+
+        Config::build(vars, file.as_ref().map(String::as_str), problems, required, demo)
+    }
+
+    fn from_sources(vars: HashMap<String, String>,
+                    file: Option<&str>)
+                    -> Result<Config, ConfigError> {
+        Config::build(vars, file, Vec::new(), REQUIRED, false)
+    }
+
+    fn build(vars: HashMap<String, String>,
+             file: Option<&str>,
+             mut problems: Vec<String>,
+             required: &[&str],
+             demo: bool)
+             -> Result<Config, ConfigError> {
+        let file = match file.map(|contents| contents.parse::<toml::Value>()) {
+            Some(Ok(value)) => Some(value),
+            Some(Err(err)) => {
+                problems.push(format!("config file is not valid TOML: {}", err));
+                None
+            }
+            None => None,
+        };
+
+        let profile = vars.get("PUPIL_ENV")
+            .and_then(|env| if env.is_empty() { None } else { Some(env.clone()) })
+            .unwrap_or(String::from(DEFAULT_PROFILE));
+
+        let mut sources = Sources {
+            vars: vars,
+            file: file,
+            profile: profile,
+            problems: problems,
+        };
+
+        let (database_url, jwt_secret, hash_secret) = if demo {
+            (sources.lookup("DATABASE_URL").unwrap_or_default(),
+             sources.lookup("JWT_SECRET").unwrap_or_else(random_secret),
+             sources.lookup("HASH_SECRET").unwrap_or_else(random_secret))
+        } else {
+            (sources.required_if("DATABASE_URL", required.contains(&"DATABASE_URL")),
+             sources.required_if("JWT_SECRET", required.contains(&"JWT_SECRET")),
+             sources.required_if("HASH_SECRET", required.contains(&"HASH_SECRET")))
+        };
+        let database_replica_url = sources.lookup("DATABASE_REPLICA_URL")
+            .and_then(|url| if url.is_empty() { None } else { Some(url) });
+        let pool_size = sources.number("DATABASE_POOL_SIZE", DEFAULT_POOL_SIZE);
+        let pool_min_idle = sources.optional_number("DATABASE_MIN_IDLE");
+        let pool_connection_timeout =
+            sources.number("DATABASE_CONNECTION_TIMEOUT", DEFAULT_POOL_CONNECTION_TIMEOUT);
+        // zero turns the limit off
+        let pool_max_lifetime = match sources.number("DATABASE_MAX_LIFETIME",
+                                                     DEFAULT_POOL_MAX_LIFETIME) {
+            0 => None,
+            seconds => Some(seconds as u64),
+        };
+        let pool_connect_retries =
+            sources.number("DATABASE_CONNECT_RETRIES", DEFAULT_POOL_CONNECT_RETRIES);
+        let run_migrations = sources.flag("RUN_MIGRATIONS", false);
+        let hash_workers = sources.number("HASH_WORKERS", DEFAULT_HASH_WORKERS);
+        let hash_queue_depth = sources.number("HASH_QUEUE_DEPTH", DEFAULT_HASH_QUEUE_DEPTH);
+        let static_root = sources.lookup("STATIC_ROOT")
+            .unwrap_or_else(|| String::from(DEFAULT_STATIC_ROOT));
+        let assets_from_disk = sources.flag("ASSETS_FROM_DISK", false);
+        // an empty policy in the config file (or a *_FILE) leaves that header
+        // off, as does an HSTS max age of zero
+        let content_security_policy = sources.lookup("CONTENT_SECURITY_POLICY")
+            .unwrap_or_else(|| String::from(DEFAULT_CONTENT_SECURITY_POLICY));
+        let csp_report_only = sources.flag("CSP_REPORT_ONLY", false);
+        let hsts_max_age = sources.number("HSTS_MAX_AGE", DEFAULT_HSTS_MAX_AGE);
+        let referrer_policy = sources.lookup("REFERRER_POLICY")
+            .unwrap_or_else(|| String::from(DEFAULT_REFERRER_POLICY));
+        let permissions_policy = sources.lookup("PERMISSIONS_POLICY")
+            .unwrap_or_else(|| String::from(DEFAULT_PERMISSIONS_POLICY));
+        // production is served over https; elsewhere it usually isn't
+        let production = sources.profile == "production";
+        let cookie_secure = sources.flag("COOKIE_SECURE", production);
+        let cookie_secret = sources.lookup("COOKIE_SECRET")
+            .and_then(|secret| if secret.is_empty() { None } else { Some(secret) });
+        // a comma separated list; with none, no other origin may call the api
+        let cors_origins: Vec<String> = sources.lookup("CORS_ORIGINS")
+            .map(|origins| {
+                origins.split(',')
+                    .map(|origin| origin.trim().trim_right_matches('/'))
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let cors_methods = sources.lookup("CORS_METHODS")
+            .unwrap_or_else(|| String::from(DEFAULT_CORS_METHODS));
+        let cors_headers = sources.lookup("CORS_HEADERS")
+            .unwrap_or_else(|| String::from(DEFAULT_CORS_HEADERS));
+        let cors_credentials = sources.flag("CORS_CREDENTIALS", false);
+        let cors_max_age = sources.number("CORS_MAX_AGE", DEFAULT_CORS_MAX_AGE);
+        // PEM, usually read from a file with JWT_PRIVATE_KEY_FILE; without a
+        // private key jwts are signed with JWT_SECRET
+        let jwt_algorithm = sources.lookup("JWT_ALGORITHM");
+        let jwt_private_key = sources.lookup("JWT_PRIVATE_KEY")
+            .and_then(|key| if key.is_empty() { None } else { Some(key) });
+        let jwt_public_keys = sources.lookup("JWT_PUBLIC_KEYS")
+            .and_then(|keys| if keys.is_empty() { None } else { Some(keys) });
+        // seconds a jwt's exp and nbf may be off by, for clocks that drift
+        let jwt_leeway = sources.number("JWT_LEEWAY", DEFAULT_JWT_LEEWAY);
+        // without one /metrics isn't served at all
+        let metrics_token = sources.lookup("METRICS_TOKEN")
+            .and_then(|token| if token.is_empty() { None } else { Some(token) });
+
+        let log_level = match sources.lookup("LOG_LEVEL") {
+            Some(level) => {
+                level.parse().unwrap_or_else(|_| {
+                    sources.problems
+                        .push(format!("LOG_LEVEL must be one of off, error, warn, info, debug \
+                                       or trace, not {}",
+                                      level));
+                    LogLevelFilter::Info
+                })
+            }
+            None => LogLevelFilter::Info,
+        };
+
+        let log_format = match sources.lookup("LOG_FORMAT").as_ref().map(String::as_str) {
+            None | Some("logfmt") => logging::Format::Logfmt,
+            Some("json") => logging::Format::Json,
+            Some(other) => {
+                sources.problems.push(format!("LOG_FORMAT must be logfmt or json, not {}", other));
+                logging::Format::Logfmt
+            }
+        };
+
+        if hash_workers == 0 {
+            sources.problems.push(String::from("HASH_WORKERS must be at least 1"));
+        }
+
+        let backend = Backend::of(&database_url);
+        if backend.is_none() && !database_url.is_empty() {
+            sources.problems
+                .push(String::from("DATABASE_URL must be a postgres:// or sqlite:// URL"));
+        } else if backend == Some(Backend::Sqlite) &&
+                  database::sqlite_is_private(database::sqlite_path(&database_url)) {
+            // every pooled connection would open a separate, empty database
+            sources.problems
+                .push(String::from("DATABASE_URL must name a sqlite file, not :memory:"));
+        }
+
+        if let Some(ref url) = database_replica_url {
+            if Backend::of(url) != Some(Backend::Postgres) {
+                sources.problems
+                    .push(String::from("DATABASE_REPLICA_URL must be a postgres:// URL"));
+            } else if backend == Some(Backend::Sqlite) {
+                sources.problems
+                    .push(String::from("DATABASE_REPLICA_URL can't be used with sqlite"));
+            }
+        }
+
+        for origin in &cors_origins {
+            if origin == "*" {
+                if cors_credentials {
+                    sources.problems
+                        .push(String::from("CORS_ORIGINS can't be * with CORS_CREDENTIALS"));
+                }
+            } else if !origin.starts_with("https://") && !origin.starts_with("http://") {
+                sources.problems.push(format!("CORS_ORIGINS: {} is not an http(s) origin", origin));
+            }
+        }
+
+        if jwt_public_keys.is_some() && jwt_private_key.is_none() {
+            sources.problems.push(String::from("JWT_PUBLIC_KEYS needs JWT_PRIVATE_KEY"));
+        }
+
+        if pool_size == 0 {
+            sources.problems.push(String::from("DATABASE_POOL_SIZE must be at least 1"));
+        }
+
+        if pool_min_idle.map_or(false, |min_idle| min_idle > pool_size) {
+            sources.problems
+                .push(String::from("DATABASE_MIN_IDLE must not be more than DATABASE_POOL_SIZE"));
+        }
+
+        if pool_connection_timeout == 0 {
+            sources.problems.push(String::from("DATABASE_CONNECTION_TIMEOUT must be at least 1"));
+        }
+
+        if sources.problems.is_empty() {
+            Ok(Config {
+                profile: sources.profile,
+                database_url: database_url,
+                database_replica_url: database_replica_url,
+                pool_size: pool_size as u32,
+                pool_min_idle: pool_min_idle.map(|min_idle| min_idle as u32),
+                pool_connection_timeout: pool_connection_timeout as u64,
+                pool_max_lifetime: pool_max_lifetime,
+                pool_connect_retries: pool_connect_retries as u32,
+                jwt_secret: jwt_secret,
+                hash_secret: hash_secret,
+                run_migrations: run_migrations,
+                log_level: log_level,
+                log_format: log_format,
+                hash_workers: hash_workers,
+                hash_queue_depth: hash_queue_depth,
+                static_root: static_root,
+                assets_from_disk: assets_from_disk,
+                content_security_policy: content_security_policy,
+                csp_report_only: csp_report_only,
+                hsts_max_age: hsts_max_age as u64,
+                referrer_policy: referrer_policy,
+                permissions_policy: permissions_policy,
+                cookie_secure: cookie_secure,
+                cookie_secret: cookie_secret,
+                cors_origins: cors_origins,
+                cors_methods: cors_methods,
+                cors_headers: cors_headers,
+                cors_credentials: cors_credentials,
+                cors_max_age: cors_max_age as u64,
+                jwt_algorithm: jwt_algorithm,
+                jwt_private_key: jwt_private_key,
+                jwt_public_keys: jwt_public_keys,
+                jwt_leeway: jwt_leeway as i64,
+                metrics_token: metrics_token,
+            })
+        } else {
+            Err(ConfigError { problems: sources.problems })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(k, v)| (String::from(k), String::from(v))).collect()
+    }
+
+    #[test]
+    fn from_env() {
+        let config = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                                 ("JWT_SECRET", "jwt"),
+                                                 ("HASH_SECRET", "hash")]),
+                                          None)
+            .unwrap();
+
+        assert_eq!(config.profile, "development");
+        assert_eq!(config.database_url, "postgres://localhost/pupil");
+        assert_eq!(config.jwt_secret, "jwt");
+        assert_eq!(config.hash_secret, "hash");
+        assert!(!config.run_migrations);
+        assert_eq!(config.database_replica_url, None);
+        assert_eq!(config.pool_size, DEFAULT_POOL_SIZE as u32);
+        assert_eq!(config.pool_max_lifetime, Some(DEFAULT_POOL_MAX_LIFETIME as u64));
+        assert_eq!(config.hash_workers, DEFAULT_HASH_WORKERS);
+        assert_eq!(config.hash_queue_depth, DEFAULT_HASH_QUEUE_DEPTH);
+        assert_eq!(config.static_root, DEFAULT_STATIC_ROOT);
+        assert!(!config.assets_from_disk);
+        assert_eq!(config.content_security_policy, DEFAULT_CONTENT_SECURITY_POLICY);
+        assert!(!config.csp_report_only);
+        assert_eq!(config.hsts_max_age, DEFAULT_HSTS_MAX_AGE as u64);
+        assert!(!config.cookie_secure);
+        assert_eq!(config.cookie_secret, None);
+        assert!(config.cors_origins.is_empty());
+        assert!(!config.cors_credentials);
+        assert_eq!(config.jwt_private_key, None);
+        assert_eq!(config.jwt_leeway, DEFAULT_JWT_LEEWAY as i64);
+    }
+
+    #[test]
+    fn security_headers() {
+        let config = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                                 ("JWT_SECRET", "jwt"),
+                                                 ("HASH_SECRET", "hash"),
+                                                 ("CONTENT_SECURITY_POLICY",
+                                                  "default-src 'self'"),
+                                                 ("CSP_REPORT_ONLY", "true"),
+                                                 ("HSTS_MAX_AGE", "0")]),
+                                          Some("[default]\npermissions_policy = \"\""))
+            .unwrap();
+
+        assert_eq!(config.content_security_policy, "default-src 'self'");
+        assert!(config.csp_report_only);
+        assert_eq!(config.hsts_max_age, 0);
+        assert_eq!(config.referrer_policy, DEFAULT_REFERRER_POLICY);
+        assert_eq!(config.permissions_policy, "");
+    }
+
+    #[test]
+    fn profile_overrides_default() {
+        let file = r#"
+            [default]
+            database_url = "postgres://localhost/pupil"
+            jwt_secret = "default"
+            hash_secret = "hash"
+
+            [production]
+            jwt_secret = "production"
+            run_migrations = true
+        "#;
+
+        let config = Config::from_sources(vars(&[("PUPIL_ENV", "production")]), Some(file))
+            .unwrap();
+
+        assert_eq!(config.profile, "production");
+        assert_eq!(config.jwt_secret, "production");
+        assert_eq!(config.hash_secret, "hash");
+        assert!(config.run_migrations);
+        assert!(config.cookie_secure);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let file = r#"
+            [default]
+            database_url = "postgres://localhost/pupil"
+            jwt_secret = "file"
+            hash_secret = "hash"
+        "#;
+
+        let config = Config::from_sources(vars(&[("JWT_SECRET", "env")]), Some(file)).unwrap();
+
+        assert_eq!(config.jwt_secret, "env");
+    }
+
+    #[test]
+    fn pool_settings() {
+        let config = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                                 ("DATABASE_REPLICA_URL",
+                                                  "postgres://replica/pupil"),
+                                                 ("DATABASE_POOL_SIZE", "4"),
+                                                 ("DATABASE_MIN_IDLE", "1"),
+                                                 ("DATABASE_MAX_LIFETIME", "0"),
+                                                 ("JWT_SECRET", "jwt"),
+                                                 ("HASH_SECRET", "hash")]),
+                                          None)
+            .unwrap();
+
+        assert_eq!(config.database_replica_url, Some(String::from("postgres://replica/pupil")));
+        assert_eq!(config.pool_size, 4);
+        assert_eq!(config.pool_min_idle, Some(1));
+        assert_eq!(config.pool_max_lifetime, None);
+    }
+
+    #[test]
+    fn bad_pool_settings() {
+        let err = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                              ("DATABASE_POOL_SIZE", "2"),
+                                              ("DATABASE_MIN_IDLE", "3"),
+                                              ("DATABASE_CONNECTION_TIMEOUT", "soon"),
+                                              ("JWT_SECRET", "jwt"),
+                                              ("HASH_SECRET", "hash")]),
+                                       None)
+            .unwrap_err();
+
+        assert_eq!(err.problems.len(), 2);
+        assert!(err.problems.iter().any(|p| p.starts_with("DATABASE_MIN_IDLE")));
+        assert!(err.problems.iter().any(|p| p.starts_with("DATABASE_CONNECTION_TIMEOUT")));
+    }
+
+    #[test]
+    fn sqlite_database() {
+        let config = Config::from_sources(vars(&[("DATABASE_URL", "sqlite://pupil.db"),
+                                                 ("JWT_SECRET", "jwt"),
+                                                 ("HASH_SECRET", "hash")]),
+                                          None)
+            .unwrap();
+
+        assert_eq!(config.database_url, "sqlite://pupil.db");
+
+        let err = Config::from_sources(vars(&[("DATABASE_URL", "sqlite://pupil.db"),
+                                              ("DATABASE_REPLICA_URL", "postgres://replica/pupil"),
+                                              ("JWT_SECRET", "jwt"),
+                                              ("HASH_SECRET", "hash")]),
+                                       None)
+            .unwrap_err();
+
+        assert_eq!(err.problems, vec!["DATABASE_REPLICA_URL can't be used with sqlite"]);
+
+        let err = Config::from_sources(vars(&[("DATABASE_URL", "sqlite::memory:"),
+                                              ("JWT_SECRET", "jwt"),
+                                              ("HASH_SECRET", "hash")]),
+                                       None)
+            .unwrap_err();
+
+        assert_eq!(err.problems, vec!["DATABASE_URL must name a sqlite file, not :memory:"]);
+    }
+
+    #[test]
+    fn cors_origins() {
+        let config = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                                 ("JWT_SECRET", "jwt"),
+                                                 ("HASH_SECRET", "hash"),
+                                                 ("CORS_ORIGINS",
+                                                  "https://app.pupil.io/, http://localhost:8080"),
+                                                 ("CORS_CREDENTIALS", "true")]),
+                                          None)
+            .unwrap();
+
+        assert_eq!(config.cors_origins, vec!["https://app.pupil.io", "http://localhost:8080"]);
+        assert!(config.cors_credentials);
+
+        let err = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                              ("JWT_SECRET", "jwt"),
+                                              ("HASH_SECRET", "hash"),
+                                              ("CORS_ORIGINS", "*, app.pupil.io"),
+                                              ("CORS_CREDENTIALS", "true")]),
+                                       None)
+            .unwrap_err();
+
+        assert_eq!(err.problems.len(), 2);
+    }
+
+    #[test]
+    fn demo_needs_no_database() {
+        let config = Config::build(vars(&[]), None, Vec::new(), &[], true).unwrap();
+
+        assert_eq!(config.database_url, "");
+        assert_eq!(config.jwt_secret.len(), 64);
+        assert!(config.jwt_secret != config.hash_secret);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let err = Config::from_sources(vars(&[("DATABASE_URL", "mysql://localhost/pupil"),
+                                              ("HASH_SECRET_FILE", "/does/not/exist")]),
+                                       None)
+            .unwrap_err();
+
+        assert_eq!(err.problems.len(), 4);
+        assert!(err.problems.iter().any(|p| p.starts_with("JWT_SECRET")));
+        assert!(err.problems.iter().any(|p| p.starts_with("HASH_SECRET_FILE")));
+        assert!(err.problems.iter().any(|p| p.starts_with("DATABASE_URL")));
+    }
+
+    #[test]
+    fn empty_env_is_unset() {
+        let file = r#"
+            [default]
+            jwt_secret = "from-file"
+        "#;
+        let err = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                              ("JWT_SECRET", ""),
+                                              ("HASH_SECRET", ""),
+                                              ("REFERRER_POLICY", "")]),
+                                       Some(file))
+            .unwrap_err();
+
+        assert_eq!(err.problems, vec![String::from("HASH_SECRET is not set")]);
+
+        let config = Config::from_sources(vars(&[("DATABASE_URL", "postgres://localhost/pupil"),
+                                                 ("JWT_SECRET", ""),
+                                                 ("HASH_SECRET", "hash"),
+                                                 ("REFERRER_POLICY", "")]),
+                                          Some(file))
+            .unwrap();
+
+        assert_eq!(config.jwt_secret, "from-file");
+        assert_eq!(config.referrer_policy, DEFAULT_REFERRER_POLICY);
+    }
+
+    #[test]
+    fn only_what_is_required() {
+        let config = Config::build(vars(&[("DATABASE_URL", "postgres://localhost/pupil")]),
+                                   None,
+                                   Vec::new(),
+                                   &["DATABASE_URL"],
+                                   false)
+            .unwrap();
+
+        assert_eq!(config.jwt_secret, "");
+        assert_eq!(config.hash_secret, "");
+
+        let err = Config::build(vars(&[]), None, Vec::new(), &["HASH_SECRET"], false)
+            .unwrap_err();
+
+        assert_eq!(err.problems, vec![String::from("HASH_SECRET is not set")]);
+    }
+
+    #[test]
+    fn unreadable_config_file() {
+        let err = Config::load_from(vars(&[("PUPIL_CONFIG", "/does/not/exist.toml"),
+                                           ("DATABASE_URL", "mysql://localhost/pupil")]),
+                                    REQUIRED,
+                                    false)
+            .unwrap_err();
+
+        assert_eq!(err.problems.len(), 4);
+        assert!(err.problems.iter().any(|p| p.starts_with("/does/not/exist.toml")));
+        assert!(err.problems.iter().any(|p| p.starts_with("JWT_SECRET")));
+        assert!(err.problems.iter().any(|p| p.starts_with("HASH_SECRET")));
+        assert!(err.problems.iter().any(|p| p.starts_with("DATABASE_URL")));
+    }
+}
@@ -0,0 +1,187 @@
+//! Cross-origin access for a frontend hosted somewhere else, like a CDN.
+//!
+//! Only the origins in `CORS_ORIGINS` get the `Access-Control-Allow-*`
+//! headers. For them to sign in with the auth cookie, `CORS_CREDENTIALS` has
+//! to be on and the frontend has to be on the same site as the api (say
+//! `app.pupil.io` and `api.pupil.io`), since the cookie is `SameSite=Lax`.
+//! `csrf::check` lets the same origins through.
+
+use std::path::PathBuf;
+
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method};
+use rocket::response::status;
+
+use super::config::Config;
+
+/// Whether `origin` is one of `origins`. Schemes and hosts don't care about
+/// case, so neither does the comparison; anything else has to match exactly.
+pub fn is_allowed(origins: &[String], origin: &str) -> bool {
+    origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+}
+
+/// Adds the CORS headers to responses for allowed origins, answering both
+/// preflights and the requests that follow them.
+pub struct Cors {
+    origins: Vec<String>,
+    methods: String,
+    headers: String,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl Cors {
+    pub fn new(config: &Config) -> Self {
+        Cors {
+            origins: config.cors_origins.clone(),
+            methods: config.cors_methods.clone(),
+            headers: config.cors_headers.clone(),
+            credentials: config.cors_credentials,
+            max_age: config.cors_max_age,
+        }
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if self.origins.is_empty() {
+            return;
+        }
+
+        // caches must not hand one origin's answer to another
+        response.adjoin_header(Header::new("Vary", "Origin"));
+
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) if is_allowed(&self.origins, origin) => String::from(origin),
+            _ => return,
+        };
+
+        response.set_header(Header::new("Access-Control-Allow-Origin", origin));
+        if self.credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        let preflight = request.method() == Method::Options &&
+                        request.headers().contains("Access-Control-Request-Method");
+        if preflight {
+            response.set_header(Header::new("Access-Control-Allow-Methods", self.methods.clone()));
+            response.set_header(Header::new("Access-Control-Allow-Headers", self.headers.clone()));
+            response.set_header(Header::new("Access-Control-Max-Age", self.max_age.to_string()));
+        } else {
+            response.set_header(Header::new("Access-Control-Expose-Headers", "X-Request-Id"));
+        }
+    }
+}
+
+/// Answers every preflight under the api, where it is mounted. Whether the
+/// browser goes ahead is down to the headers `Cors` adds.
+#[options("/<_path..>")]
+fn preflight(_path: PathBuf) -> status::NoContent {
+    status::NoContent
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rocket;
+    use rocket::Rocket;
+    use rocket::testing::MockRequest;
+    use rocket::http::Status;
+
+    #[get("/me")]
+    fn me() -> &'static str {
+        "me"
+    }
+
+    fn rocket(origins: &[&str], credentials: bool) -> Rocket {
+        let cors = Cors {
+            origins: origins.iter().map(|origin| String::from(*origin)).collect(),
+            methods: String::from("GET, POST"),
+            headers: String::from("Content-Type"),
+            credentials: credentials,
+            max_age: 600,
+        };
+
+        rocket::ignite().attach(cors).mount("/api/v1", routes![me, preflight])
+    }
+
+    fn preflight_request(origin: &str) -> MockRequest {
+        MockRequest::new(Method::Options, "/api/v1/me")
+            .header(Header::new("Origin", String::from(origin)))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+    }
+
+    #[test]
+    fn allowed_preflight() {
+        let rocket = rocket(&["https://app.pupil.io"], true);
+
+        let mut req = preflight_request("https://app.pupil.io");
+        let response = req.dispatch_with(&rocket);
+        let headers = response.headers();
+
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(headers.get_one("Access-Control-Allow-Origin"), Some("https://app.pupil.io"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Credentials"), Some("true"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Methods"), Some("GET, POST"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Headers"), Some("Content-Type"));
+        assert_eq!(headers.get_one("Access-Control-Max-Age"), Some("600"));
+    }
+
+    #[test]
+    fn other_origins_get_nothing() {
+        let rocket = rocket(&["https://app.pupil.io"], true);
+
+        let mut req = preflight_request("https://evil.example");
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.headers().get_one("Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn actual_requests() {
+        let rocket = rocket(&["*"], false);
+
+        let mut req = MockRequest::new(Method::Get, "/api/v1/me")
+            .header(Header::new("Origin", "https://anywhere.example"));
+        let response = req.dispatch_with(&rocket);
+        let headers = response.headers();
+
+        assert_eq!(headers.get_one("Access-Control-Allow-Origin"),
+                   Some("https://anywhere.example"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Credentials"), None);
+        assert_eq!(headers.get_one("Access-Control-Allow-Methods"), None);
+        assert_eq!(headers.get_one("Access-Control-Expose-Headers"), Some("X-Request-Id"));
+    }
+
+    #[test]
+    fn origin_case() {
+        let origins = vec![String::from("https://App.Pupil.io")];
+
+        assert!(is_allowed(&origins, "https://app.pupil.io"));
+        assert!(is_allowed(&origins, "HTTPS://APP.PUPIL.IO"));
+        assert!(!is_allowed(&origins, "https://app.pupil.io:8443"));
+        assert!(!is_allowed(&origins, "https://app.pupil.io.evil.example"));
+    }
+
+    #[test]
+    fn off_without_origins() {
+        let rocket = rocket(&[], false);
+
+        let mut req = MockRequest::new(Method::Get, "/api/v1/me")
+            .header(Header::new("Origin", "https://app.pupil.io"));
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.headers().get_one("Vary"), None);
+    }
+}
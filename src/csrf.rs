@@ -0,0 +1,165 @@
+use rocket::request;
+use rocket::outcome::Outcome;
+use rocket::{Request, State};
+use rocket::http::{Method, Status};
+
+use super::config::Config;
+use super::cors;
+use super::error::Error;
+
+/// Request guard for state-changing routes that a browser could be tricked
+/// into calling from another site. The session cookie is `SameSite=Lax`, and
+/// on top of that any `Origin` or `Referer` the browser sends has to point at
+/// this host, or be one of the origins allowed to call us with credentials.
+pub struct SameOrigin;
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for SameOrigin {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<SameOrigin, Error> {
+        match check(request) {
+            Ok(()) => Outcome::Success(SameOrigin),
+            Err(err) => Outcome::Failure((Status::Forbidden, err)),
+        }
+    }
+}
+
+fn is_safe(method: Method) -> bool {
+    match method {
+        Method::Get | Method::Head | Method::Options => true,
+        _ => false,
+    }
+}
+
+/// Pulls the `host[:port]` out of an `Origin` or `Referer` value.
+fn origin_host(url: &str) -> Option<&str> {
+    let rest = match url.find("://") {
+        Some(index) => &url[index + 3..],
+        None => return None,
+    };
+
+    let host = match rest.find('/') {
+        Some(index) => &rest[..index],
+        None => rest,
+    };
+
+    if host.is_empty() { None } else { Some(host) }
+}
+
+/// The `scheme://host[:port]` an `Origin` or `Referer` value comes from.
+fn origin_of(url: &str) -> Option<&str> {
+    // the host starts right after the scheme; searching for it could match
+    // inside the scheme instead, as with `http://http/`
+    origin_host(url).map(|host| {
+        let end = url.find("://").unwrap() + 3 + host.len();
+        &url[..end]
+    })
+}
+
+// a frontend hosted elsewhere only sends the cookie along with credentials
+fn trusted_cross_origin(request: &Request, source: &str) -> bool {
+    let config = match request.guard::<State<Config>>() {
+        Outcome::Success(config) => config,
+        _ => return false,
+    };
+
+    config.cors_credentials &&
+    origin_of(source).map_or(false, |origin| cors::is_allowed(&config.cors_origins, origin))
+}
+
+pub fn check(request: &Request) -> Result<(), Error> {
+    if is_safe(request.method()) {
+        return Ok(());
+    }
+
+    let headers = request.headers();
+    let source = match headers.get_one("Origin").or_else(|| headers.get_one("Referer")) {
+        Some(source) => source,
+        // browsers send at least one of these on cross-site posts; scripts and
+        // apps using bearer tokens send neither
+        None => return Ok(()),
+    };
+
+    match (origin_host(source), headers.get_one("Host")) {
+        (Some(origin), Some(host)) if origin.eq_ignore_ascii_case(host) => Ok(()),
+        _ if trusted_cross_origin(request, source) => Ok(()),
+        _ => Err(Error::CrossOrigin),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rocket;
+    use rocket::testing::MockRequest;
+    use rocket::http::{Status, Method, Header};
+
+    use super::super::config::Config;
+
+    #[post("/mutate")]
+    fn mutate(_origin: SameOrigin) -> &'static str {
+        "ok"
+    }
+
+    #[test]
+    fn parse_origin_host() {
+        assert_eq!(origin_host("https://pupil.io"), Some("pupil.io"));
+        assert_eq!(origin_host("http://localhost:8000/dash"), Some("localhost:8000"));
+        assert_eq!(origin_host("null"), None);
+    }
+
+    #[test]
+    fn parse_origin() {
+        assert_eq!(origin_of("https://app.pupil.io/dash?tab=1"), Some("https://app.pupil.io"));
+        assert_eq!(origin_of("http://localhost:8080"), Some("http://localhost:8080"));
+        assert_eq!(origin_of("null"), None);
+    }
+
+    #[test]
+    fn parse_origin_with_repeated_host() {
+        assert_eq!(origin_of("http://http/dash"), Some("http://http"));
+        assert_eq!(origin_of("https://s:8443/"), Some("https://s:8443"));
+        assert_eq!(origin_of("https://evil.com@evil.com/"), Some("https://evil.com@evil.com"));
+    }
+
+    #[test]
+    fn same_origin_allowed() {
+        let rocket = rocket::ignite().mount("/", routes![mutate]);
+        let mut req = MockRequest::new(Method::Post, "/mutate")
+            .header(Header::new("Host", "pupil.io"))
+            .header(Header::new("Origin", "https://pupil.io"));
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn cross_origin_rejected() {
+        let rocket = rocket::ignite().mount("/", routes![mutate]);
+        let mut req = MockRequest::new(Method::Post, "/mutate")
+            .header(Header::new("Host", "pupil.io"))
+            .header(Header::new("Referer", "https://evil.example/page"));
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn cors_origins_allowed() {
+        let mut config = Config::load_demo().unwrap();
+        config.cors_origins = vec![String::from("https://app.pupil.io")];
+        config.cors_credentials = true;
+
+        let rocket = rocket::ignite().manage(config).mount("/", routes![mutate]);
+        let mut req = MockRequest::new(Method::Post, "/mutate")
+            .header(Header::new("Host", "api.pupil.io"))
+            .header(Header::new("Origin", "https://app.pupil.io"));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+
+        let mut req = MockRequest::new(Method::Post, "/mutate")
+            .header(Header::new("Host", "api.pupil.io"))
+            .header(Header::new("Origin", "https://app.pupil.io.evil.example"));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+    }
+}
@@ -1,17 +1,224 @@
+use std::cmp;
 use std::thread;
-use std::env;
+use std::time::{Duration, Instant};
 
 use r2d2;
+use r2d2::{CustomizeConnection, GetTimeout, InitializationError, PooledConnection};
+use diesel::connection::{Connection, SimpleConnection};
 use diesel::pg::PgConnection;
-use r2d2_diesel::ConnectionManager;
+use diesel::sqlite::SqliteConnection;
+use r2d2_diesel::{self, ConnectionManager};
 
-pub struct ConnectionPool(pub r2d2::Pool<ConnectionManager<PgConnection>>);
+use log::LogLevel;
 
-impl ConnectionPool {
-    pub fn new() -> Self {
-        let config = r2d2::Config::default();
-        let manager = ConnectionManager::<PgConnection>::new(env::var("DATABASE_URL")
-            .expect("DATABASE_URL must be set"));
-        ConnectionPool(r2d2::Pool::new(config, manager).expect("Failed to create pool."))
+use super::config::Config;
+use super::logging;
+use super::metrics;
+
+pub type Pool<C> = r2d2::Pool<ConnectionManager<C>>;
+
+const MAX_RETRY_DELAY_SECS: u64 = 30;
+
+/// The kinds of database `DATABASE_URL` can point at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Postgres,
+    Sqlite,
+}
+
+impl Backend {
+    /// Picks the backend from the scheme of `url`.
+    pub fn of(url: &str) -> Option<Backend> {
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            Some(Backend::Postgres)
+        } else if url.starts_with("sqlite:") {
+            Some(Backend::Sqlite)
+        } else {
+            None
+        }
+    }
+}
+
+/// The file a `sqlite:` URL names: `sqlite://pupil.db` and `sqlite:pupil.db`
+/// both open `pupil.db`.
+pub fn sqlite_path(url: &str) -> &str {
+    let path = url.trim_left_matches("sqlite:");
+    if path.starts_with("//") { &path[2..] } else { path }
+}
+
+/// Whether every connection to the sqlite database at `path` gets a private
+/// database of its own, which a pool would hand out at random.
+pub fn sqlite_is_private(path: &str) -> bool {
+    path.is_empty() || path == ":memory:"
+}
+
+/// A database we can keep a pool of connections to.
+pub trait Database: Connection + Send + 'static {
+    fn manager(url: &str) -> ConnectionManager<Self>;
+
+    /// Run on every connection as it is opened.
+    fn customizer() -> Box<CustomizeConnection<Self, r2d2_diesel::Error>>;
+}
+
+impl Database for PgConnection {
+    fn manager(url: &str) -> ConnectionManager<Self> {
+        ConnectionManager::new(url)
+    }
+
+    fn customizer() -> Box<CustomizeConnection<Self, r2d2_diesel::Error>> {
+        Box::new(r2d2::NopConnectionCustomizer)
+    }
+}
+
+#[derive(Debug)]
+struct SqlitePragmas;
+
+impl CustomizeConnection<SqliteConnection, r2d2_diesel::Error> for SqlitePragmas {
+    fn on_acquire(&self, connection: &mut SqliteConnection) -> Result<(), r2d2_diesel::Error> {
+        // sqlite ignores foreign keys, and so `on delete cascade`, unless asked
+        // not to, and fails straight away when another connection is writing
+        connection.batch_execute("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000")
+            .map_err(r2d2_diesel::Error::QueryError)
+    }
+}
+
+impl Database for SqliteConnection {
+    fn manager(url: &str) -> ConnectionManager<Self> {
+        ConnectionManager::new(sqlite_path(url))
+    }
+
+    fn customizer() -> Box<CustomizeConnection<Self, r2d2_diesel::Error>> {
+        Box::new(SqlitePragmas)
+    }
+}
+
+/// The primary database pool, plus a pool for a read replica if one is
+/// configured.
+///
+/// Reads from the replica can lag slightly behind writes to the primary, so
+/// only queries that can tolerate that should use `get_read`.
+pub struct ConnectionPool<C: Database = PgConnection> {
+    primary: Pool<C>,
+    replica: Option<Pool<C>>,
+}
+
+impl<C: Database> Clone for ConnectionPool<C> {
+    fn clone(&self) -> Self {
+        ConnectionPool {
+            primary: self.primary.clone(),
+            replica: self.replica.clone(),
+        }
+    }
+}
+
+fn connect<C: Database>(url: &str, config: &Config) -> Result<Pool<C>, InitializationError> {
+    let mut attempt = 0;
+
+    loop {
+        let pool_config = r2d2::Config::builder()
+            .pool_size(config.pool_size)
+            .min_idle(config.pool_min_idle)
+            .connection_timeout(Duration::from_secs(config.pool_connection_timeout))
+            .max_lifetime(config.pool_max_lifetime.map(Duration::from_secs))
+            .connection_customizer(C::customizer())
+            .build();
+
+        match r2d2::Pool::new(pool_config, C::manager(url)) {
+            Ok(pool) => return Ok(pool),
+            Err(err) => {
+                if attempt >= config.pool_connect_retries {
+                    return Err(err);
+                }
+
+                // postgres is often still starting when we are, so back off
+                // rather than failing the whole deploy
+                let delay = cmp::min(1 << cmp::min(attempt, 5), MAX_RETRY_DELAY_SECS);
+                logging::event(LogLevel::Warn,
+                               "could not connect to the database, retrying",
+                               &[("error", &err.to_string()), ("retry_in", &delay.to_string())]);
+                thread::sleep(Duration::from_secs(delay));
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn checkout<C: Database>(pool: &Pool<C>,
+                         name: &str)
+                         -> Result<PooledConnection<ConnectionManager<C>>, GetTimeout> {
+    let started = Instant::now();
+    let connection = pool.get();
+
+    metrics::POOL_WAIT.observe(&[name], metrics::seconds(started.elapsed()));
+    if connection.is_err() {
+        metrics::POOL_TIMEOUTS.inc(&[name]);
+    }
+
+    connection
+}
+
+impl<C: Database> ConnectionPool<C> {
+    /// Connects to the primary and any replica, retrying with backoff before
+    /// giving up.
+    pub fn new(config: &Config) -> Result<Self, InitializationError> {
+        let primary = connect(&config.database_url, config)?;
+        let replica = match config.database_replica_url {
+            Some(ref url) => Some(connect(url, config)?),
+            None => None,
+        };
+
+        Ok(ConnectionPool {
+            primary: primary,
+            replica: replica,
+        })
+    }
+
+    /// Checks out a connection to the primary, recording how long it took.
+    pub fn get(&self) -> Result<PooledConnection<ConnectionManager<C>>, GetTimeout> {
+        checkout(&self.primary, "primary")
+    }
+
+    /// Checks out a connection for read-only queries, from the replica if
+    /// there is one.
+    pub fn get_read(&self) -> Result<PooledConnection<ConnectionManager<C>>, GetTimeout> {
+        match self.replica {
+            Some(ref replica) => checkout(replica, "replica"),
+            None => self.get(),
+        }
+    }
+
+    /// The state of each pool by name, for reporting.
+    pub fn states(&self) -> Vec<(&'static str, r2d2::State)> {
+        let mut states = vec![("primary", self.primary.state())];
+        if let Some(ref replica) = self.replica {
+            states.push(("replica", replica.state()));
+        }
+        states
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backend_from_scheme() {
+        assert_eq!(Backend::of("postgres://localhost/pupil"), Some(Backend::Postgres));
+        assert_eq!(Backend::of("postgresql://localhost/pupil"), Some(Backend::Postgres));
+        assert_eq!(Backend::of("sqlite://pupil.db"), Some(Backend::Sqlite));
+        assert_eq!(Backend::of("sqlite::memory:"), Some(Backend::Sqlite));
+        assert_eq!(Backend::of("mysql://localhost/pupil"), None);
+        assert_eq!(Backend::of("pupil.db"), None);
+    }
+
+    #[test]
+    fn sqlite_paths() {
+        assert_eq!(sqlite_path("sqlite://pupil.db"), "pupil.db");
+        assert_eq!(sqlite_path("sqlite:/var/lib/pupil.db"), "/var/lib/pupil.db");
+        assert_eq!(sqlite_path("sqlite::memory:"), ":memory:");
+
+        assert!(sqlite_is_private(sqlite_path("sqlite::memory:")));
+        assert!(sqlite_is_private(sqlite_path("sqlite://")));
+        assert!(!sqlite_is_private(sqlite_path("sqlite://pupil.db")));
     }
 }
@@ -2,30 +2,47 @@ use std::{io, fmt, error};
 use std::error::Error as StdError;
 
 use diesel::result::Error as DieselError;
-use diesel::result::{DatabaseErrorKind, DatabaseErrorInformation};
+use diesel::result::DatabaseErrorInformation;
 
 use rocket::response::{Responder, Response};
-use rocket::http::{ContentType, Status};
+use rocket::http::{ContentType, Header, Status};
 
 use r2d2::GetTimeout;
 
+use jwt::errors::Error as JwtError;
+
 use serde_json;
 
+use log::LogLevel;
+
+use super::logging;
+
+const RETRY_AFTER_SECS: u32 = 5;
+
 #[derive(Debug)]
 pub enum Error {
     UserTaken,
     EmailTaken,
     BadUserOrPass,
+    WrongPassword,
     BadCookie,
+    BadToken,
+    MissingScope,
+    CrossOrigin,
+    Forbidden,
+    BadExportLink,
+    Overloaded,
     NotConfirmed(ThresholdKind),
     DatabaseError(DieselError),
     PoolError(GetTimeout),
+    TokenError(JwtError),
 }
 
 #[derive(Debug)]
 pub enum ThresholdKind {
     Register,
     Login,
+    EmailChange,
 }
 
 impl fmt::Display for Error {
@@ -40,7 +57,14 @@ impl error::Error for Error {
             Error::UserTaken => "That username already exists. Please choose another.",
             Error::EmailTaken => "An account with that email already exists.",
             Error::BadUserOrPass => "Username and password don't match.",
+            Error::WrongPassword => "Your current password is incorrect.",
             Error::BadCookie => "Your authentication cookie has expired.",
+            Error::BadToken => "That access token is invalid or has been revoked.",
+            Error::MissingScope => "That access token is not allowed to do this.",
+            Error::CrossOrigin => "That request came from another site and was blocked.",
+            Error::Forbidden => "You are not allowed to do that.",
+            Error::BadExportLink => "That download link has expired. Please request a new one.",
+            Error::Overloaded => "We're handling a lot of sign ins right now. Please try again.",
             Error::NotConfirmed(ref kind) => {
                 match *kind {
                     ThresholdKind::Register => {
@@ -50,10 +74,15 @@ impl error::Error for Error {
                     ThresholdKind::Login => {
                         "Please check your email and confirm your email address before signing in."
                     }
+                    ThresholdKind::EmailChange => {
+                        "Your email was updated! Please check your email and click on the link we \
+                         sent to confirm your new address."
+                    }
                 }
             }
             Error::DatabaseError(_) => "The request failed. Please reload and try again.",
             Error::PoolError(_) => "The request failed. Please reload and try again.",
+            Error::TokenError(_) => "The request failed. Please reload and try again.",
         }
     }
 
@@ -62,42 +91,268 @@ impl error::Error for Error {
     }
 }
 
+impl Error {
+    pub fn status(&self) -> Status {
+        match *self {
+            Error::BadToken => Status::Unauthorized,
+            Error::MissingScope | Error::CrossOrigin | Error::Forbidden => Status::Forbidden,
+            Error::Overloaded | Error::PoolError(_) => Status::ServiceUnavailable,
+            Error::TokenError(_) => Status::InternalServerError,
+            _ => Status::BadRequest,
+        }
+    }
+
+    /// How many seconds a client should wait before retrying, for errors
+    /// that are only temporary.
+    pub fn retry_after(&self) -> Option<u32> {
+        match *self {
+            Error::Overloaded | Error::PoolError(_) => Some(RETRY_AFTER_SECS),
+            _ => None,
+        }
+    }
+}
+
 impl<'a> Responder<'a> for Error {
     fn respond(self) -> Result<Response<'a>, Status> {
-        let body = io::Cursor::new(serde_json::to_string(self.description())
+        let body = io::Cursor::new(serde_json::to_string(&json!({
+                "error": self.description(),
+                "request_id": logging::request_id()
+            }))
             .unwrap_or(String::from("The request failed. Please reload and try again. uh oh")));
 
-        Ok(Response::build()
-            .status(Status::BadRequest)
+        let mut response = Response::build();
+        response.status(self.status())
             .header(ContentType::JSON)
-            .sized_body(body)
-            .finalize())
+            .sized_body(body);
+
+        if let Some(seconds) = self.retry_after() {
+            response.header(Header::new("Retry-After", seconds.to_string()));
+        }
+
+        Ok(response.finalize())
     }
 }
 
+const SQLITE_UNIQUE_FAILED: &'static str = "UNIQUE constraint failed: ";
+
+/// A unique constraint in the schema, and what violating it means.
+struct UniqueConstraint {
+    /// The name postgres reports, `<table>_<column>_key` unless the
+    /// migration gave it another.
+    name: &'static str,
+    /// The `<table>.<column>` sqlite reports, since it doesn't name
+    /// constraints.
+    column: &'static str,
+    /// The error to show, or `None` if a violation can only be a bug.
+    error: Option<fn() -> Error>,
+}
+
+fn email_taken() -> Error {
+    Error::EmailTaken
+}
+
+fn user_taken() -> Error {
+    Error::UserTaken
+}
+
+/// Every unique constraint the migrations create. A new unique column needs
+/// an entry here, or violating it shows up as an unexpected database error.
+static UNIQUE_CONSTRAINTS: &'static [UniqueConstraint] = &[
+    UniqueConstraint {
+        name: "users_email_key",
+        column: "users.email",
+        error: Some(email_taken),
+    },
+    UniqueConstraint {
+        name: "users_username_key",
+        column: "users.username",
+        error: Some(user_taken),
+    },
+    // public ids and the hashes of tokens are random, so a clash is a bug
+    UniqueConstraint {
+        name: "users_public_id_key",
+        column: "users.public_id",
+        error: None,
+    },
+    UniqueConstraint {
+        name: "access_tokens_token_hash_key",
+        column: "access_tokens.token_hash",
+        error: None,
+    },
+];
+
+/// The constraint a unique violation was on, from the constraint name
+/// postgres gives or, failing that, the columns in sqlite's message. Neither
+/// depends on the server's language.
+fn violated_constraint(constraint_name: Option<&str>,
+                       message: &str)
+                       -> Option<&'static UniqueConstraint> {
+    match constraint_name {
+        Some(name) => UNIQUE_CONSTRAINTS.iter().find(|constraint| constraint.name == name),
+        None if message.starts_with(SQLITE_UNIQUE_FAILED) => {
+            let column = &message[SQLITE_UNIQUE_FAILED.len()..];
+            UNIQUE_CONSTRAINTS.iter().find(|constraint| constraint.column == column)
+        }
+        None => None,
+    }
+}
+
+// errors are logged with their message only: the debug form carries
+// postgres' details, which quote the offending row's values
 impl From<DieselError> for Error {
     fn from(err: DieselError) -> Self {
-        println!("{:?}", err);
         match err {
             DieselError::NotFound => Error::BadUserOrPass,
-            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
-                let message = info.message().to_owned();
-                if message.contains("users_email_key") {
-                    Error::EmailTaken
-                } else if message.contains("users_username_key") {
-                    Error::UserTaken
-                } else {
-                    Error::DatabaseError(DieselError::DatabaseError(
-                        DatabaseErrorKind::UniqueViolation, info))
+            DieselError::DatabaseError(kind, info) => {
+                let constraint = violated_constraint(info.constraint_name(), info.message());
+                let err = DieselError::DatabaseError(kind, info);
+
+                match constraint {
+                    Some(&UniqueConstraint { error: Some(error), .. }) => error(),
+                    Some(constraint) => {
+                        logging::event(LogLevel::Error,
+                                       "unique violation",
+                                       &[("constraint", constraint.name),
+                                         ("error", &err.to_string())]);
+                        Error::DatabaseError(err)
+                    }
+                    None => {
+                        logging::event(LogLevel::Error,
+                                       "database error",
+                                       &[("error", &err.to_string())]);
+                        Error::DatabaseError(err)
+                    }
                 }
             }
-            _ => Error::DatabaseError(err),
+            _ => {
+                logging::event(LogLevel::Error,
+                               "database error",
+                               &[("error", &err.to_string())]);
+                Error::DatabaseError(err)
+            }
         }
     }
 }
 
 impl From<GetTimeout> for Error {
     fn from(err: GetTimeout) -> Self {
+        logging::event(LogLevel::Warn, "database pool timed out", &[]);
         Error::PoolError(err)
     }
 }
+
+impl From<JwtError> for Error {
+    fn from(err: JwtError) -> Self {
+        logging::event(LogLevel::Error, "jwt error", &[("error", &err.to_string())]);
+        Error::TokenError(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::migrations::MIGRATIONS;
+
+    use rocket::http::Status;
+
+    #[test]
+    fn temporary_errors_retry_after() {
+        let response = Error::Overloaded.respond().unwrap();
+
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("5"));
+    }
+
+    fn mapped(constraint_name: Option<&str>, message: &str) -> Option<String> {
+        violated_constraint(constraint_name, message)
+            .and_then(|constraint| constraint.error)
+            .map(|error| error().to_string())
+    }
+
+    #[test]
+    fn users_email_key() {
+        let taken = Some(Error::EmailTaken.to_string());
+
+        // the message is ignored when there is a constraint name, whatever
+        // language it is in
+        assert_eq!(mapped(Some("users_email_key"), "doppelter Schlüsselwert"), taken);
+        assert_eq!(mapped(None, "UNIQUE constraint failed: users.email"), taken);
+    }
+
+    #[test]
+    fn users_username_key() {
+        let taken = Some(Error::UserTaken.to_string());
+
+        assert_eq!(mapped(Some("users_username_key"), "doppelter Schlüsselwert"), taken);
+        assert_eq!(mapped(None, "UNIQUE constraint failed: users.username"), taken);
+    }
+
+    #[test]
+    fn access_tokens_token_hash_key() {
+        let postgres = violated_constraint(Some("access_tokens_token_hash_key"), "");
+        let sqlite = violated_constraint(None,
+                                         "UNIQUE constraint failed: access_tokens.token_hash");
+
+        assert!(postgres.is_some() && postgres.unwrap().error.is_none());
+        assert!(sqlite.is_some() && sqlite.unwrap().error.is_none());
+    }
+
+    #[test]
+    fn unknown_constraints() {
+        assert!(violated_constraint(Some("users_pkey"), "").is_none());
+        // an old message naming a constraint is no longer trusted
+        assert!(violated_constraint(None, "violates unique constraint \"users_email_key\"")
+            .is_none());
+        assert!(violated_constraint(None, "FOREIGN KEY constraint failed").is_none());
+    }
+
+    // the `<table>.<column>` of every column the migrations make unique, as
+    // they are written here: lower case, one column to a line
+    fn unique_columns() -> Vec<String> {
+        let mut columns = Vec::new();
+
+        for migration in MIGRATIONS {
+            let mut table = None;
+            for line in migration.up.lines() {
+                let words: Vec<&str> = line.split_whitespace()
+                    .map(|word| word.trim_right_matches(&[',', ';'][..]))
+                    .collect();
+
+                if words.starts_with(&["create", "table"]) {
+                    table = words.get(2).cloned();
+                } else if !words.contains(&"unique") {
+                    continue;
+                } else if words.starts_with(&["alter", "table"]) && words.len() > 5 {
+                    columns.push(format!("{}.{}", words[2], words[5]));
+                } else if let Some(table) = table {
+                    columns.push(format!("{}.{}", table, words[0]));
+                }
+            }
+        }
+
+        columns
+    }
+
+    #[test]
+    fn every_unique_column_is_registered() {
+        let columns = unique_columns();
+        assert!(columns.contains(&String::from("users.email")));
+        assert!(columns.contains(&String::from("users.public_id")));
+
+        for column in columns {
+            let name = format!("{}_key", column.replace('.', "_"));
+            assert!(UNIQUE_CONSTRAINTS.iter()
+                        .any(|constraint| constraint.name == name && constraint.column == column),
+                    "no entry in UNIQUE_CONSTRAINTS for {}",
+                    name);
+        }
+    }
+
+    #[test]
+    fn other_errors_do_not() {
+        let response = Error::BadUserOrPass.respond().unwrap();
+
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.headers().get_one("Retry-After"), None);
+    }
+}
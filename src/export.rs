@@ -0,0 +1,154 @@
+use std::io;
+
+use rocket::response::{Responder, Response};
+use rocket::http::{ContentType, Status};
+
+use jwt::Validation;
+
+use serde_json;
+
+use time;
+
+use super::error::Error;
+use super::keys::JwtKeys;
+use super::model::User;
+
+static EXPORT_LIFETIME: i64 = 15 * 60;
+static EXPORT_PURPOSE: &'static str = "export";
+static ISSUER: &'static str = "pupil";
+
+#[derive(Serialize)]
+pub struct AccountExport {
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    pub username: String,
+    pub conf: bool,
+    pub role: String,
+}
+
+/// Everything stored about a single user, as handed back to them on request.
+#[derive(Serialize)]
+pub struct Export {
+    pub generated_at: i64,
+    pub account: AccountExport,
+}
+
+impl Export {
+    pub fn new(user: User) -> Self {
+        Export {
+            generated_at: time::get_time().sec,
+            account: AccountExport {
+                id: user.public_id,
+                name: user.name,
+                email: user.email,
+                username: user.username,
+                conf: user.conf,
+                role: user.role,
+            },
+        }
+    }
+}
+
+impl<'a> Responder<'a> for Export {
+    fn respond(self) -> Result<Response<'a>, Status> {
+        let body = serde_json::to_string_pretty(&self).map_err(|_| Status::InternalServerError)?;
+
+        Ok(Response::build()
+            .header(ContentType::JSON)
+            .raw_header("Content-Disposition", "attachment; filename=\"pupil-export.json\"")
+            .sized_body(io::Cursor::new(body))
+            .finalize())
+    }
+}
+
+/// Claims for a short-lived download link. `purpose` keeps an auth token from
+/// being accepted as a link and the other way around.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportToken {
+    pub iat: i64,
+    pub exp: i64,
+    pub iss: String,
+    pub purpose: String,
+    pub id: String,
+}
+
+impl ExportToken {
+    pub fn new(id: &str) -> Self {
+        let now = time::get_time().sec;
+        ExportToken {
+            iat: now,
+            exp: now + EXPORT_LIFETIME,
+            iss: String::from(ISSUER),
+            purpose: String::from(EXPORT_PURPOSE),
+            id: String::from(id),
+        }
+    }
+
+    /// Signed with the same keys as auth tokens.
+    pub fn construct_jwt(&self, keys: &JwtKeys) -> Result<String, Error> {
+        Ok(keys.encode(self)?)
+    }
+
+    /// Checks a link token and returns the public id of the user it was issued
+    /// for.
+    pub fn verify(token: &str, keys: &JwtKeys) -> Option<String> {
+        let validation = Validation { iss: Some(String::from(ISSUER)), ..Default::default() };
+
+        match keys.decode::<ExportToken>(token, validation) {
+            Ok(claims) => {
+                if claims.purpose == EXPORT_PURPOSE {
+                    Some(claims.id)
+                } else {
+                    None
+                }
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::model::{UserToken, new_public_id};
+
+    fn user() -> User {
+        User {
+            id: 1,
+            public_id: new_public_id(),
+            name: String::from("John Smith"),
+            email: String::from("jsmith@website.com"),
+            username: String::from("jsmith"),
+            pass: String::from("hashed_password"),
+            conf: true,
+            role: String::from("student"),
+        }
+    }
+
+    #[test]
+    fn export_leaves_out_password() {
+        let export = serde_json::to_string(&Export::new(user())).unwrap();
+
+        assert!(export.contains("jsmith@website.com"));
+        assert!(!export.contains("hashed_password"));
+        assert!(!export.contains("\"id\":1"));
+    }
+
+    #[test]
+    fn link_roundtrip() {
+        let id = new_public_id();
+        let token = ExportToken::new(&id).construct_jwt(&JwtKeys::shared("secret")).unwrap();
+
+        assert_eq!(ExportToken::verify(&token, &JwtKeys::shared("secret")), Some(id));
+        assert_eq!(ExportToken::verify(&token, &JwtKeys::shared("other")), None);
+    }
+
+    #[test]
+    fn auth_token_is_not_a_link() {
+        let keys = JwtKeys::shared("secret");
+        let token = UserToken::new(user()).construct_jwt(&keys).unwrap();
+
+        assert_eq!(ExportToken::verify(&token, &keys), None);
+    }
+}
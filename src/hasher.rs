@@ -0,0 +1,185 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::thread;
+
+use log::LogLevel;
+
+use super::config::Config;
+use super::error::Error;
+use super::logging;
+use super::metrics;
+use super::passwd;
+
+enum Job {
+    Hash {
+        username: String,
+        pass: String,
+        secret: String,
+        reply: Sender<String>,
+    },
+    Verify {
+        hash: String,
+        pass: String,
+        reply: Sender<bool>,
+    },
+}
+
+/// A fixed set of threads that do all argon2 work, so a burst of logins can
+/// only keep that many cores busy and the rest stay free for other routes.
+///
+/// Jobs wait in a queue of bounded depth. Once it is full, new jobs are
+/// turned away with `Error::Overloaded` straight away, rather than holding
+/// one of rocket's workers while they wait their turn.
+pub struct Hasher {
+    // SyncSender can't be shared between threads on its own
+    jobs: Mutex<SyncSender<Job>>,
+}
+
+fn work(jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // the lock is only held while waiting, never while hashing
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        // a panic drops the job's reply, which fails that one request, and
+        // the worker carries on with the next job rather than dying with it
+        let done = panic::catch_unwind(AssertUnwindSafe(|| match job {
+            Job::Hash { username, pass, secret, reply } => {
+                let _ = reply.send(passwd::hash_password(&username, &pass, &secret));
+            }
+            Job::Verify { hash, pass, reply } => {
+                let _ = reply.send(passwd::verify_password(&hash, &pass));
+            }
+        }));
+
+        if done.is_err() {
+            logging::event(LogLevel::Error, "hasher job panicked", &[]);
+        }
+    }
+}
+
+impl Hasher {
+    pub fn new(config: &Config) -> Self {
+        Hasher::with_capacity(config.hash_workers, config.hash_queue_depth)
+    }
+
+    pub fn with_capacity(workers: usize, queue_depth: usize) -> Self {
+        Hasher::start(workers, queue_depth).0
+    }
+
+    /// Also hands back the queue's receiving end, which stays open for as
+    /// long as it or any worker holds it.
+    fn start(workers: usize, queue_depth: usize) -> (Self, Arc<Mutex<Receiver<Job>>>) {
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("hasher-{}", i))
+                .spawn(move || work(receiver))
+                .expect("Failed to start hasher thread.");
+        }
+
+        (Hasher { jobs: Mutex::new(sender) }, receiver)
+    }
+
+    fn submit(&self, job: Job) -> Result<(), Error> {
+        match self.jobs.lock().unwrap().try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                metrics::HASH_REJECTED.inc(&[]);
+                logging::event(LogLevel::Warn, "hasher queue full", &[]);
+                Err(Error::Overloaded)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                logging::event(LogLevel::Error, "hasher threads have stopped", &[]);
+                Err(Error::Overloaded)
+            }
+        }
+    }
+
+    pub fn hash_password(&self, username: &str, pass: &str, secret: &str) -> Result<String, Error> {
+        let (reply, result) = mpsc::channel();
+        self.submit(Job::Hash {
+                username: String::from(username),
+                pass: String::from(pass),
+                secret: String::from(secret),
+                reply: reply,
+            })?;
+
+        // only fails if the worker panicked part way through
+        result.recv().map_err(|_| Error::Overloaded)
+    }
+
+    pub fn verify_password(&self, hash: &str, pass: &str) -> Result<bool, Error> {
+        let (reply, result) = mpsc::channel();
+        self.submit(Job::Verify {
+                hash: String::from(hash),
+                pass: String::from(pass),
+                reply: reply,
+            })?;
+
+        result.recv().map_err(|_| Error::Overloaded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_and_verify() {
+        let hasher = Hasher::with_capacity(2, 4);
+
+        let hashed = hasher.hash_password("jsmith", "password", "secret").unwrap();
+
+        assert!(hasher.verify_password(&hashed, "password").unwrap());
+        assert!(!hasher.verify_password(&hashed, "wrong").unwrap());
+    }
+
+    #[test]
+    fn survives_malformed_hash() {
+        // one worker, so the second job runs on the same thread as the first
+        let hasher = Hasher::with_capacity(1, 4);
+
+        assert!(!hasher.verify_password("not an argon2 hash", "password").unwrap());
+
+        let hashed = hasher.hash_password("jsmith", "password", "secret").unwrap();
+        assert!(hasher.verify_password(&hashed, "password").unwrap());
+    }
+
+    #[test]
+    fn sheds_load_when_full() {
+        // no workers, so nothing ever leaves the queue, which is held open here
+        let (hasher, _jobs) = Hasher::start(0, 1);
+        let rejected = metrics::HASH_REJECTED.get(&[]);
+        let (reply, _result) = mpsc::channel();
+        hasher.submit(Job::Verify {
+                hash: String::new(),
+                pass: String::new(),
+                reply: reply,
+            })
+            .unwrap();
+
+        match hasher.verify_password("", "") {
+            Err(Error::Overloaded) => {}
+            other => panic!("expected Overloaded, got {:?}", other),
+        }
+        assert!(metrics::HASH_REJECTED.get(&[]) > rejected);
+    }
+
+    #[test]
+    fn overloaded_without_workers() {
+        // the queue closes as soon as no worker is left to read it
+        let hasher = Hasher::with_capacity(0, 1);
+
+        match hasher.verify_password("", "") {
+            Err(Error::Overloaded) => {}
+            other => panic!("expected Overloaded, got {:?}", other),
+        }
+    }
+}
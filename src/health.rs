@@ -0,0 +1,166 @@
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::Duration;
+
+use rocket::State;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::{JSON, Value};
+
+use log::LogLevel;
+
+use super::store::Store;
+use super::logging;
+
+const READY_TIMEOUT_MS: u64 = 2000;
+
+/// Set while a database check runs. A check can outlive the request that
+/// started it, and while it does every other probe is answered without one,
+/// so a dead database never piles up more than one waiting thread.
+static CHECKING: AtomicBool = ATOMIC_BOOL_INIT;
+
+struct Checking;
+
+impl Checking {
+    fn start() -> Option<Checking> {
+        if CHECKING.compare_and_swap(false, true, Ordering::SeqCst) {
+            None
+        } else {
+            Some(Checking)
+        }
+    }
+}
+
+impl Drop for Checking {
+    fn drop(&mut self) {
+        CHECKING.store(false, Ordering::SeqCst);
+    }
+}
+
+#[get("/healthz")]
+fn healthz() -> JSON<Value> {
+    JSON(json!({ "status": "ok" }))
+}
+
+#[get("/readyz")]
+fn readyz(store: State<Store>) -> status::Custom<JSON<Value>> {
+    let checking = match Checking::start() {
+        Some(checking) => checking,
+        None => {
+            return status::Custom(Status::ServiceUnavailable,
+                                  JSON(json!({
+                                      "status": "unavailable",
+                                      "database": "still checking"
+                                  })))
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let store = store.inner().clone();
+
+    // the pool blocks for its whole connection timeout when postgres is gone,
+    // which is far longer than a load balancer will wait
+    thread::spawn(move || {
+        let _checking = checking;
+        let _ = sender.send(store.pending_migrations());
+    });
+
+    match receiver.recv_timeout(Duration::from_millis(READY_TIMEOUT_MS)) {
+        Ok(Ok(ref pending)) if pending.is_empty() => {
+            status::Custom(Status::Ok,
+                           JSON(json!({
+                               "status": "ready",
+                               "database": "ok",
+                               "pending_migrations": pending
+                           })))
+        }
+        Ok(Ok(pending)) => {
+            status::Custom(Status::ServiceUnavailable,
+                           JSON(json!({
+                               "status": "unavailable",
+                               "database": "ok",
+                               "pending_migrations": pending
+                           })))
+        }
+        Ok(Err(err)) => {
+            // the probe is public, so the details only go to the log, which
+            // ties them to this request's id
+            logging::event(LogLevel::Error, "readiness", &[("database", &err)]);
+            status::Custom(Status::ServiceUnavailable,
+                           JSON(json!({
+                               "status": "unavailable",
+                               "database": "unavailable"
+                           })))
+        }
+        Err(_) => {
+            status::Custom(Status::ServiceUnavailable,
+                           JSON(json!({
+                               "status": "unavailable",
+                               "database": "timed out"
+                           })))
+        }
+    }
+}
+
+#[get("/version")]
+fn version() -> JSON<Value> {
+    JSON(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("PUPIL_GIT_COMMIT"),
+        "built_at": env!("PUPIL_BUILD_TIME")
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing::TestDb;
+
+    use rocket;
+    use rocket::testing::MockRequest;
+    use rocket::http::{Status, Method};
+
+    use serde_json::{self, Value};
+
+    #[test]
+    fn healthz() {
+        let rocket = rocket::ignite().mount("/", routes![super::healthz]);
+        let mut req = MockRequest::new(Method::Get, "/healthz");
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn version() {
+        let rocket = rocket::ignite().mount("/", routes![super::version]);
+        let mut req = MockRequest::new(Method::Get, "/version");
+        let mut response = req.dispatch_with(&rocket);
+
+        let body: Value = response.body()
+            .and_then(|b| b.into_string())
+            .and_then(|b| serde_json::from_str(&b).ok())
+            .unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(body["version"], json!(env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn readyz() {
+        let db = TestDb::new();
+
+        let rocket = db.rocket().mount("/", routes![super::readyz]);
+        let mut req = MockRequest::new(Method::Get, "/readyz");
+        let mut response = req.dispatch_with(&rocket);
+
+        let body: Value = response.body()
+            .and_then(|b| b.into_string())
+            .and_then(|b| serde_json::from_str(&b).ok())
+            .unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(body["status"], json!("ready"));
+        assert_eq!(body["pending_migrations"], json!([]));
+    }
+}
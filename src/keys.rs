@@ -0,0 +1,486 @@
+//! The keys jwts are signed and checked with.
+//!
+//! Without `JWT_PRIVATE_KEY` tokens are signed with HS256 and `JWT_SECRET`,
+//! so anything that checks them can also mint them. With an RSA private key
+//! (PKCS#1 or PKCS#8 PEM) they are signed with RS256, or the RS384 or RS512
+//! picked with `JWT_ALGORITHM`, and carry the key's id as `kid`. The public
+//! half of that key and of any in `JWT_PUBLIC_KEYS` are accepted, and
+//! published at `/.well-known/jwks.json`, so a key can be rotated by moving
+//! the old one there until its tokens have expired.
+
+use std::fmt::Write;
+
+use jwt::{encode, decode, decode_header, Algorithm, Header, Validation};
+use jwt::errors::{Error as JwtError, ErrorKind};
+
+use ring::digest;
+
+use base64;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use rocket::State;
+use rocket_contrib::JSON;
+
+use super::config::Config;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_SEQUENCE: u8 = 0x30;
+
+struct VerifyingKey {
+    kid: Option<String>,
+    key: Vec<u8>,
+    // the modulus and exponent of an RSA key
+    rsa: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Handed to rocket as managed state.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    kid: Option<String>,
+    signing: Vec<u8>,
+    verifying: Vec<VerifyingKey>,
+}
+
+impl JwtKeys {
+    pub fn new(config: &Config) -> Result<JwtKeys, String> {
+        let private_key = match config.jwt_private_key {
+            Some(ref private_key) => private_key,
+            None => {
+                return match config.jwt_algorithm.as_ref().map(String::as_str) {
+                    None | Some("HS256") => Ok(JwtKeys::shared(&config.jwt_secret)),
+                    Some(other) => {
+                        Err(format!("JWT_ALGORITHM {} needs JWT_PRIVATE_KEY, or HS256", other))
+                    }
+                };
+            }
+        };
+
+        let algorithm = match config.jwt_algorithm.as_ref().map(String::as_str) {
+            None | Some("RS256") => Algorithm::RS256,
+            Some("RS384") => Algorithm::RS384,
+            Some("RS512") => Algorithm::RS512,
+            Some(other) => {
+                return Err(format!("JWT_ALGORITHM must be RS256, RS384 or RS512 with a \
+                                    private key, not {}",
+                                   other))
+            }
+        };
+
+        let signing = match pem_blocks(private_key)?.into_iter().next() {
+            Some((ref label, ref der)) if label == "RSA PRIVATE KEY" => der.clone(),
+            Some((ref label, ref der)) if label == "PRIVATE KEY" => {
+                pkcs8_private_key(der).ok_or("JWT_PRIVATE_KEY is not an RSA private key")?
+            }
+            _ => return Err(String::from("JWT_PRIVATE_KEY must be an RSA PRIVATE KEY in PEM")),
+        };
+
+        let (n, e) = private_key_components(&signing)
+            .ok_or("JWT_PRIVATE_KEY is not an RSA private key")?;
+        let current = VerifyingKey::rsa(n, e);
+        let kid = current.kid.clone();
+
+        let mut verifying = vec![current];
+        if let Some(ref public_keys) = config.jwt_public_keys {
+            for (label, der) in pem_blocks(public_keys)? {
+                let public_key = match label.as_str() {
+                    "RSA PUBLIC KEY" => Some(der),
+                    "PUBLIC KEY" => spki_public_key(&der),
+                    _ => None,
+                };
+                let (n, e) = public_key.as_ref()
+                    .and_then(|der| public_key_components(der))
+                    .ok_or("JWT_PUBLIC_KEYS must only hold RSA public keys")?;
+                verifying.push(VerifyingKey::rsa(n, e));
+            }
+        }
+
+        let keys = JwtKeys {
+            algorithm: algorithm,
+            kid: kid,
+            signing: signing,
+            verifying: verifying,
+        };
+
+        // ring turns down keys shorter than 2048 bits, among others
+        keys.encode(&json!({}))
+            .map_err(|err| format!("JWT_PRIVATE_KEY can't be used to sign: {}", err))?;
+
+        Ok(keys)
+    }
+
+    /// Keys that sign and check with HS256 and `secret`.
+    pub fn shared(secret: &str) -> JwtKeys {
+        JwtKeys {
+            algorithm: Algorithm::HS256,
+            kid: None,
+            signing: secret.as_bytes().to_vec(),
+            verifying: vec![VerifyingKey {
+                                kid: None,
+                                key: secret.as_bytes().to_vec(),
+                                rsa: None,
+                            }],
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, JwtError> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+
+        encode(&header, claims, &self.signing)
+    }
+
+    /// Checks `token` against the key its `kid` names, and only with the
+    /// algorithm we sign with.
+    pub fn decode<T: DeserializeOwned>(&self,
+                                       token: &str,
+                                       mut validation: Validation)
+                                       -> Result<T, JwtError> {
+        let header = decode_header(token)?;
+        validation.algorithms = vec![self.algorithm];
+
+        match self.verifying.iter().find(|key| key.kid == header.kid) {
+            Some(key) => decode::<T>(token, &key.key, &validation).map(|data| data.claims),
+            None => Err(ErrorKind::InvalidSignature.into()),
+        }
+    }
+
+    /// The public keys as a JSON Web Key Set. With a shared secret there are
+    /// none to publish.
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<Value> = self.verifying
+            .iter()
+            .filter_map(|key| {
+                key.rsa.as_ref().map(|&(ref n, ref e)| {
+                    json!({
+                        "kty": "RSA",
+                        "use": "sig",
+                        "alg": format!("{:?}", self.algorithm),
+                        "kid": key.kid,
+                        "n": base64url(n),
+                        "e": base64url(e)
+                    })
+                })
+            })
+            .collect();
+
+        json!({ "keys": keys })
+    }
+}
+
+impl VerifyingKey {
+    fn rsa(n: Vec<u8>, e: Vec<u8>) -> Self {
+        let key = der_element(TAG_SEQUENCE,
+                              &[der_element(TAG_INTEGER, &n), der_element(TAG_INTEGER, &e)]
+                                  .concat());
+
+        // the first 8 bytes of the public key's SHA-256
+        let mut kid = String::new();
+        for byte in &digest::digest(&digest::SHA256, &key).as_ref()[..8] {
+            write!(kid, "{:02x}", byte).unwrap();
+        }
+
+        VerifyingKey {
+            kid: Some(kid),
+            key: key,
+            rsa: Some((n, e)),
+        }
+    }
+}
+
+#[get("/.well-known/jwks.json")]
+fn jwks(keys: State<JwtKeys>) -> JSON<Value> {
+    JSON(keys.jwks())
+}
+
+/// The label and decoded contents of each PEM block.
+fn pem_blocks(pem: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<(String, String)> = None;
+
+    for line in pem.lines().map(str::trim) {
+        if line.starts_with("-----BEGIN ") {
+            let label = line.trim_left_matches("-----BEGIN ").trim_right_matches('-');
+            current = Some((String::from(label), String::new()));
+        } else if line.starts_with("-----END ") {
+            let (label, body) = current.take().ok_or("PEM block ends before it begins")?;
+            let der = base64::decode(&body)
+                .map_err(|err| format!("bad PEM in {}: {}", label, err))?;
+            blocks.push((label, der));
+        } else if let Some((_, ref mut body)) = current {
+            body.push_str(line);
+        }
+    }
+
+    if blocks.is_empty() {
+        Err(String::from("no PEM blocks found"))
+    } else {
+        Ok(blocks)
+    }
+}
+
+/// Splits off the first DER element, returning its tag, its contents and
+/// what follows it.
+fn der_split(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    if input.len() < 2 {
+        return None;
+    }
+
+    let (length, start) = match input[1] {
+        length if length < 0x80 => (length as usize, 2),
+        0x81...0x84 => {
+            let count = (input[1] & 0x7f) as usize;
+            if input.len() < 2 + count {
+                return None;
+            }
+            let length = input[2..2 + count].iter().fold(0, |n, &b| (n << 8) | b as usize);
+            (length, 2 + count)
+        }
+        _ => return None,
+    };
+
+    // a four byte length can't fit in a 32 bit usize alongside its header
+    match start.checked_add(length) {
+        Some(end) if end <= input.len() => Some((input[0], &input[start..end], &input[end..])),
+        _ => None,
+    }
+}
+
+fn der_expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match der_split(input) {
+        Some((found, contents, rest)) if found == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+fn der_element(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+
+    let length = contents.len();
+    if length < 0x80 {
+        element.push(length as u8);
+    } else {
+        let bytes: Vec<u8> = (0..4)
+            .rev()
+            .map(|i| (length >> (8 * i)) as u8)
+            .skip_while(|&b| b == 0)
+            .collect();
+        element.push(0x80 | bytes.len() as u8);
+        element.extend(bytes);
+    }
+
+    element.extend_from_slice(contents);
+    element
+}
+
+/// The PKCS#1 key inside a PKCS#8 `PRIVATE KEY`.
+fn pkcs8_private_key(der: &[u8]) -> Option<Vec<u8>> {
+    der_expect(der, TAG_SEQUENCE)
+        .and_then(|(info, _)| der_expect(info, TAG_INTEGER))
+        .and_then(|(_, rest)| der_expect(rest, TAG_SEQUENCE))
+        .and_then(|(_, rest)| der_expect(rest, TAG_OCTET_STRING))
+        .map(|(key, _)| key.to_vec())
+}
+
+/// The PKCS#1 key inside an X.509 `PUBLIC KEY`.
+fn spki_public_key(der: &[u8]) -> Option<Vec<u8>> {
+    der_expect(der, TAG_SEQUENCE)
+        .and_then(|(info, _)| der_expect(info, TAG_SEQUENCE))
+        .and_then(|(_, rest)| der_expect(rest, TAG_BIT_STRING))
+        // the first byte counts the unused bits at the end, always none here
+        .and_then(|(bits, _)| if bits.first() == Some(&0) {
+            Some(bits[1..].to_vec())
+        } else {
+            None
+        })
+}
+
+fn private_key_components(der: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    der_expect(der, TAG_SEQUENCE)
+        .and_then(|(key, _)| der_expect(key, TAG_INTEGER))
+        .and_then(|(_, rest)| public_key_fields(rest))
+}
+
+fn public_key_components(der: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    der_expect(der, TAG_SEQUENCE).and_then(|(key, _)| public_key_fields(key))
+}
+
+fn public_key_fields(fields: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    der_expect(fields, TAG_INTEGER).and_then(|(n, rest)| {
+        der_expect(rest, TAG_INTEGER).map(|(e, _)| (n.to_vec(), e.to_vec()))
+    })
+}
+
+/// An unsigned big-endian integer as a JWK wants it, without the sign byte
+/// DER puts in front of a set high bit.
+fn base64url(integer: &[u8]) -> String {
+    let start = integer.iter().position(|&b| b != 0).unwrap_or(integer.len());
+    base64::encode_config(&integer[start..], base64::URL_SAFE).trim_right_matches('=').to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use time;
+
+    const CURRENT: &'static str = include_str!("../fixtures/jwt/current.pem");
+    const PREVIOUS: &'static str = include_str!("../fixtures/jwt/previous.pem");
+    const PREVIOUS_PUBLIC: &'static str = include_str!("../fixtures/jwt/previous.pub.pem");
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Claims {
+        exp: i64,
+        iss: String,
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            exp: time::get_time().sec + 60,
+            iss: String::from("pupil"),
+        }
+    }
+
+    fn validation() -> Validation {
+        Validation { iss: Some(String::from("pupil")), ..Default::default() }
+    }
+
+    fn jwt_keys(algorithm: Option<&str>, private: Option<&str>, public: Option<&str>) -> JwtKeys {
+        let mut config = Config::load_demo().unwrap();
+        config.jwt_secret = String::from("secret");
+        config.jwt_algorithm = algorithm.map(String::from);
+        config.jwt_private_key = private.map(String::from);
+        config.jwt_public_keys = public.map(String::from);
+
+        JwtKeys::new(&config).unwrap()
+    }
+
+    #[test]
+    fn shared_secret() {
+        let keys = jwt_keys(None, None, None);
+        let token = keys.encode(&claims()).unwrap();
+
+        assert_eq!(decode_header(&token).unwrap().alg, Algorithm::HS256);
+        assert_eq!(keys.decode::<Claims>(&token, validation()).unwrap(), claims());
+        assert_eq!(keys.jwks(), json!({ "keys": [] }));
+    }
+
+    #[test]
+    fn rsa_signing() {
+        let keys = jwt_keys(None, Some(CURRENT), None);
+        let token = keys.encode(&claims()).unwrap();
+        let header = decode_header(&token).unwrap();
+
+        assert_eq!(header.alg, Algorithm::RS256);
+        assert_eq!(header.kid, keys.kid);
+        assert_eq!(keys.decode::<Claims>(&token, validation()).unwrap(), claims());
+
+        let shared = jwt_keys(None, None, None).encode(&claims()).unwrap();
+        assert!(keys.decode::<Claims>(&shared, validation()).is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        let previous = jwt_keys(Some("RS256"), Some(PREVIOUS), None);
+        let current = jwt_keys(Some("RS256"), Some(CURRENT), Some(PREVIOUS_PUBLIC));
+        let unrelated = jwt_keys(Some("RS256"), Some(CURRENT), None);
+
+        let old_token = previous.encode(&claims()).unwrap();
+
+        assert!(current.decode::<Claims>(&old_token, validation()).is_ok());
+        assert!(unrelated.decode::<Claims>(&old_token, validation()).is_err());
+        assert!(previous.decode::<Claims>(&current.encode(&claims()).unwrap(), validation())
+            .is_err());
+    }
+
+    #[test]
+    fn publishes_jwks() {
+        let keys = jwt_keys(Some("RS512"), Some(CURRENT), Some(PREVIOUS_PUBLIC));
+        let jwks = keys.jwks();
+        let published = jwks["keys"].as_array().unwrap();
+
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0]["kid"], json!(keys.kid));
+        assert_eq!(published[0]["alg"], json!("RS512"));
+        assert_eq!(published[0]["e"], json!("AQAB"));
+        // a 2048 bit modulus, without padding or sign byte
+        assert_eq!(published[0]["n"].as_str().unwrap().len(), 342);
+        assert!(published[0]["kid"] != published[1]["kid"]);
+    }
+
+    #[test]
+    fn bad_keys() {
+        let mut config = Config::load_demo().unwrap();
+        config.jwt_private_key = Some(String::from(PREVIOUS_PUBLIC));
+        assert!(JwtKeys::new(&config).is_err());
+
+        config.jwt_private_key = Some(String::from("not a key"));
+        assert!(JwtKeys::new(&config).is_err());
+
+        config.jwt_private_key = Some(String::from(CURRENT));
+        config.jwt_algorithm = Some(String::from("HS256"));
+        assert!(JwtKeys::new(&config).is_err());
+    }
+
+    #[test]
+    fn der_lengths() {
+        let long = vec![7u8; 300];
+        let element = der_element(TAG_OCTET_STRING, &long);
+
+        assert_eq!(&element[..4], &[TAG_OCTET_STRING, 0x82, 0x01, 0x2c]);
+        assert_eq!(der_split(&element), Some((TAG_OCTET_STRING, &long[..], &[][..])));
+    }
+
+    #[test]
+    fn truncated_der() {
+        let element = der_element(TAG_OCTET_STRING, &vec![7u8; 300]);
+
+        assert_eq!(der_split(&[]), None);
+        assert_eq!(der_split(&[TAG_INTEGER]), None);
+        // the length says 0x012c bytes, but the second length byte is missing
+        assert_eq!(der_split(&element[..3]), None);
+        // the contents stop short
+        assert_eq!(der_split(&element[..element.len() - 1]), None);
+
+        let key = pem_blocks(CURRENT).unwrap().remove(0).1;
+        assert!(private_key_components(&key).is_some());
+        assert_eq!(private_key_components(&key[..key.len() / 2]), None);
+    }
+
+    #[test]
+    fn overlong_der_lengths() {
+        // more length bytes than we accept
+        assert_eq!(der_split(&[TAG_INTEGER, 0x85, 0, 0, 0, 0, 1, 7]), None);
+        // indefinite lengths aren't DER
+        assert_eq!(der_split(&[TAG_INTEGER, 0x80, 7, 0, 0]), None);
+        // a length far past the end of the input
+        assert_eq!(der_split(&[TAG_INTEGER, 0x84, 0xff, 0xff, 0xff, 0xff, 7]), None);
+        assert_eq!(der_split(&[TAG_INTEGER, 0x02, 7]), None);
+    }
+
+    #[test]
+    fn wrong_der_tags() {
+        let integer = der_element(TAG_INTEGER, &[7]);
+
+        assert_eq!(der_expect(&integer, TAG_SEQUENCE), None);
+        assert_eq!(der_expect(&integer, TAG_INTEGER), Some((&[7u8][..], &[][..])));
+
+        // a key whose outer sequence is tagged as an octet string
+        let mut key = pem_blocks(CURRENT).unwrap().remove(0).1;
+        key[0] = TAG_OCTET_STRING;
+        assert_eq!(private_key_components(&key), None);
+
+        // a PKCS#1 key is not a PKCS#8 one
+        let pkcs1 = der_element(TAG_SEQUENCE,
+                                &[der_element(TAG_INTEGER, &[0]),
+                                  der_element(TAG_INTEGER, &[1, 0, 1]),
+                                  der_element(TAG_INTEGER, &[3])]
+                                    .concat());
+        assert_eq!(pkcs8_private_key(&pkcs1), None);
+        assert_eq!(spki_public_key(&pkcs1), None);
+    }
+}
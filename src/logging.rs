@@ -0,0 +1,247 @@
+use std::cell::RefCell;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+
+use rocket::{Request, Response, Data};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+
+use rand::{thread_rng, Rng};
+
+use serde_json;
+
+use time;
+
+pub const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+
+/// Field names whose values never make it into the logs.
+const SENSITIVE: &'static [&'static str] = &["password",
+                                             "pass",
+                                             "current_password",
+                                             "new_password",
+                                             "secret",
+                                             "token",
+                                             "jwt",
+                                             "authorization",
+                                             "cookie"];
+
+thread_local! {
+    // rocket handles a request start to finish on one worker thread
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// The id of the request being handled on this thread, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Logfmt,
+    Json,
+}
+
+struct Logger {
+    level: LogLevelFilter,
+    format: Format,
+}
+
+fn redact<'a>(key: &str, value: &'a str) -> &'a str {
+    if SENSITIVE.iter().any(|sensitive| key.eq_ignore_ascii_case(sensitive)) {
+        "[redacted]"
+    } else {
+        value
+    }
+}
+
+fn logfmt_value(out: &mut String, value: &str) {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        out.push_str(value);
+    } else {
+        out.push_str(&format!("{:?}", value));
+    }
+}
+
+/// Renders one log line. The message of a record logged with `event` is
+/// already a list of fields, so it is spliced in as is.
+fn render(format: Format,
+          level: LogLevel,
+          target: &str,
+          fields: &[(String, String)],
+          request_id: Option<String>)
+          -> String {
+    let ts = time::now_utc().rfc3339().to_string();
+
+    let mut all = vec![(String::from("ts"), ts),
+                       (String::from("level"), level.to_string().to_lowercase()),
+                       (String::from("target"), String::from(target))];
+    if let Some(id) = request_id {
+        all.push((String::from("request_id"), id));
+    }
+    all.extend(fields.iter().map(|&(ref k, ref v)| (k.clone(), String::from(redact(k, v)))));
+
+    match format {
+        Format::Logfmt => {
+            let mut out = String::new();
+            for (i, &(ref key, ref value)) in all.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write!(out, "{}=", key).unwrap();
+                logfmt_value(&mut out, value);
+            }
+            out
+        }
+        Format::Json => {
+            let map: serde_json::Map<String, serde_json::Value> = all.into_iter()
+                .map(|(k, v)| (k, serde_json::Value::String(v)))
+                .collect();
+            serde_json::to_string(&map).unwrap()
+        }
+    }
+}
+
+/// Splits a message produced by `event` back into fields. Anything else,
+/// like rocket's own output, becomes a single `msg` field.
+fn parse_fields(message: &str) -> Vec<(String, String)> {
+    match serde_json::from_str::<Vec<(String, String)>>(message) {
+        Ok(fields) => fields,
+        Err(_) => vec![(String::from("msg"), String::from(message))],
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let fields = parse_fields(&record.args().to_string());
+        let line = render(self.format, record.level(), record.target(), &fields, request_id());
+
+        let _ = writeln!(io::stderr(), "{}", line);
+    }
+}
+
+/// Installs the logger. This has to happen before rocket is ignited, or
+/// rocket installs its own.
+pub fn init(level: LogLevelFilter, format: Format) -> Result<(), SetLoggerError> {
+    log::set_logger(|max_level| {
+        max_level.set(level);
+        Box::new(Logger {
+            level: level,
+            format: format,
+        })
+    })
+}
+
+/// Logs a structured event. Sensitive fields are redacted on output.
+pub fn event(level: LogLevel, msg: &str, fields: &[(&str, &str)]) {
+    let mut all = vec![(String::from("msg"), String::from(msg))];
+    all.extend(fields.iter().map(|&(k, v)| (String::from(k), String::from(v))));
+
+    log!(target: "pupil", level, "{}", serde_json::to_string(&all).unwrap());
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 &&
+    id.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+        _ => false,
+    })
+}
+
+/// Gives every request an id, reusing one set by a trusted proxy in front of
+/// us when it looks sane, and echoes it back in `X-Request-Id`.
+pub struct RequestIds;
+
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let id = match request.headers().get_one(REQUEST_ID_HEADER) {
+            Some(id) if valid_request_id(id) => String::from(id),
+            _ => {
+                let mut bytes = [0u8; 8];
+                thread_rng().fill_bytes(&mut bytes);
+                bytes.iter().map(|b| format!("{:02x}", b)).collect()
+            }
+        };
+
+        request.replace_header(Header::new(REQUEST_ID_HEADER, id.clone()));
+        REQUEST_ID.with(|current| *current.borrow_mut() = Some(id));
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        if let Some(id) = REQUEST_ID.with(|current| current.borrow_mut().take()) {
+            response.set_header(Header::new(REQUEST_ID_HEADER, id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (String::from(k), String::from(v))).collect()
+    }
+
+    #[test]
+    fn redacts_sensitive_fields() {
+        let line = render(Format::Logfmt,
+                          LogLevel::Info,
+                          "pupil",
+                          &fields(&[("msg", "login"),
+                                    ("username", "jsmith"),
+                                    ("password", "hunter2")]),
+                          Some(String::from("abc123")));
+
+        assert!(line.contains("request_id=abc123"));
+        assert!(line.contains("username=jsmith"));
+        assert!(line.contains("password=[redacted]"));
+        assert!(!line.contains("hunter2"));
+    }
+
+    #[test]
+    fn logfmt_quotes_spaces() {
+        let line = render(Format::Logfmt,
+                          LogLevel::Warn,
+                          "pupil",
+                          &fields(&[("msg", "pool timed out")]),
+                          None);
+
+        assert!(line.contains("level=warn"));
+        assert!(line.contains("msg=\"pool timed out\""));
+    }
+
+    #[test]
+    fn json_lines() {
+        let line = render(Format::Json,
+                          LogLevel::Error,
+                          "pupil",
+                          &fields(&[("msg", "database error"), ("jwt", "eyJ...")]),
+                          None);
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(parsed["msg"], json!("database error"));
+        assert_eq!(parsed["jwt"], json!("[redacted]"));
+    }
+
+    #[test]
+    fn plain_messages() {
+        assert_eq!(parse_fields("Rocket has launched"),
+                   fields(&[("msg", "Rocket has launched")]));
+    }
+}
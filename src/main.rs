@@ -6,6 +6,7 @@
 extern crate dotenv;
 extern crate rocket_contrib;
 extern crate rocket;
+extern crate serde;
 #[macro_use]
 extern crate serde_json;
 #[macro_use]
@@ -20,22 +21,116 @@ extern crate diesel;
 extern crate diesel_codegen;
 extern crate r2d2;
 extern crate r2d2_diesel;
+extern crate ring;
+extern crate base64;
+extern crate toml;
+extern crate clap;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
 
-use dotenv::dotenv;
+use std::process;
 
 mod schema;
 mod model;
 mod passwd;
+mod hasher;
 mod server;
+mod api;
+mod auth;
+mod csrf;
+mod cors;
+mod export;
+mod health;
 mod database;
 mod error;
+mod config;
+mod migrations;
+mod cli;
+mod logging;
+mod metrics;
+mod store;
+mod assets;
+mod security;
+mod session;
+mod keys;
+#[cfg(test)]
+mod testing;
+#[cfg(all(test, feature = "bench"))]
+mod bench;
 
-use database::ConnectionPool;
+use rocket::Rocket;
+use log::LogLevel;
+
+use assets::Assets;
+use hasher::Hasher;
+use keys::JwtKeys;
+use security::SecurityHeaders;
+use config::Config;
+use cors::Cors;
+use store::{Store, MemoryStore};
 
 fn main() {
-    dotenv().ok();
-    rocket::ignite()
-        .manage(ConnectionPool::new())
+    let matches = cli::app().get_matches();
+    let demo = matches.is_present("demo") && matches.subcommand_name().is_none();
+
+    let loaded = if demo {
+        Config::load_demo()
+    } else {
+        Config::load_requiring(cli::required_settings(&matches))
+    };
+
+    let config = match loaded {
+        Ok(config) => config,
+        Err(err) => {
+            eprint!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    if let Some(result) = cli::run(&matches, &config) {
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(err) = logging::init(config.log_level, config.log_format) {
+        eprintln!("could not set up logging: {}", err);
+    }
+
+    let rocket = if demo {
+        logging::event(LogLevel::Info,
+                       "demo",
+                       &[("users", "student, tutor, admin"), ("password", store::DEMO_PASSWORD)]);
+        rocket::ignite().manage(Store::new(MemoryStore::demo(&config.hash_secret)))
+    } else {
+        database_rocket(&config)
+    };
+
+    let keys = match JwtKeys::new(&config) {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    // the landing page shows images hosted elsewhere
+    let security_headers = SecurityHeaders::new(&config)
+        .allow("/", "img-src", "https://i.imgur.com")
+        .allow("/", "img-src", "https://i.ytimg.com");
+
+    rocket.attach(logging::RequestIds)
+        .attach(metrics::RequestMetrics)
+        .attach(security_headers)
+        .attach(Cors::new(&config))
+        .manage(Hasher::new(&config))
+        .manage(keys)
+        .manage(Assets::new(&config))
+        .manage(config)
         .mount("/",
                routes![server::index,
                        server::dash,
@@ -43,6 +138,42 @@ fn main() {
                        server::register,
                        server::logout,
                        server::favicon,
-                       server::file])
+                       server::file,
+                       health::healthz,
+                       health::readyz,
+                       health::version,
+                       metrics::metrics,
+                       security::csp_report,
+                       keys::jwks])
+        .mount("/api/v1",
+               routes![api::me,
+                       api::update_me,
+                       api::change_password,
+                       api::delete_me,
+                       api::export_me,
+                       api::export_link,
+                       api::export_download,
+                       api::tokens,
+                       api::create_token,
+                       api::revoke_token,
+                       cors::preflight])
+        .catch(errors![server::forbidden])
         .launch();
 }
+
+fn database_rocket(config: &Config) -> Rocket {
+    if config.run_migrations {
+        if let Err(err) = cli::migrate(config, "up") {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    match Store::connect(config) {
+        Ok(store) => rocket::ignite().manage(store),
+        Err(err) => {
+            eprintln!("could not connect to the database: {}", err);
+            process::exit(1);
+        }
+    }
+}
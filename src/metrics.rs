@@ -0,0 +1,390 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::{Request, Response, Data, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::response::content::Content;
+use rocket::http::{ContentType, Status};
+
+use ring::constant_time;
+
+use super::config::Config;
+use super::store::Store;
+
+const LATENCY_BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+                                          5.0, 10.0];
+
+/// A counter split by label values, rendered in the Prometheus text format.
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        CounterVec {
+            name: name,
+            help: help,
+            labels: labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        let key = labels.iter().map(|l| String::from(*l)).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, labels: &[&str]) -> u64 {
+        let key: Vec<String> = labels.iter().map(|l| String::from(*l)).collect();
+        self.values.lock().unwrap().get(&key).cloned().unwrap_or(0)
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} counter", self.name).unwrap();
+        for (key, value) in self.values.lock().unwrap().iter() {
+            writeln!(out, "{}{} {}", self.name, label_set(self.labels, key, None), value).unwrap();
+        }
+    }
+}
+
+struct Buckets {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A histogram split by label values.
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Buckets>>,
+}
+
+impl HistogramVec {
+    fn new(name: &'static str,
+           help: &'static str,
+           labels: &'static [&'static str],
+           buckets: &'static [f64])
+           -> Self {
+        HistogramVec {
+            name: name,
+            help: help,
+            labels: labels,
+            buckets: buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, labels: &[&str], value: f64) {
+        let key = labels.iter().map(|l| String::from(*l)).collect();
+        let mut values = self.values.lock().unwrap();
+        let entry = values.entry(key).or_insert_with(|| {
+            Buckets {
+                counts: vec![0; self.buckets.len()],
+                sum: 0.0,
+                count: 0,
+            }
+        });
+
+        for (i, bound) in self.buckets.iter().enumerate() {
+            if value <= *bound {
+                entry.counts[i] += 1;
+            }
+        }
+        entry.sum += value;
+        entry.count += 1;
+    }
+
+    /// Times `f` and records how long it took, in seconds.
+    pub fn time<T, F: FnOnce() -> T>(&self, labels: &[&str], f: F) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.observe(labels, seconds(started.elapsed()));
+        result
+    }
+
+    fn render(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} histogram", self.name).unwrap();
+        for (key, buckets) in self.values.lock().unwrap().iter() {
+            for (bound, count) in self.buckets.iter().zip(buckets.counts.iter()) {
+                writeln!(out,
+                         "{}_bucket{} {}",
+                         self.name,
+                         label_set(self.labels, key, Some(&bound.to_string())),
+                         count)
+                    .unwrap();
+            }
+            writeln!(out,
+                     "{}_bucket{} {}",
+                     self.name,
+                     label_set(self.labels, key, Some("+Inf")),
+                     buckets.count)
+                .unwrap();
+            writeln!(out,
+                     "{}_sum{} {}",
+                     self.name,
+                     label_set(self.labels, key, None),
+                     buckets.sum)
+                .unwrap();
+            writeln!(out,
+                     "{}_count{} {}",
+                     self.name,
+                     label_set(self.labels, key, None),
+                     buckets.count)
+                .unwrap();
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn label_set(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names.iter()
+        .zip(values.iter())
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+lazy_static! {
+    pub static ref REQUESTS: CounterVec =
+        CounterVec::new("pupil_http_requests_total",
+                        "HTTP requests handled, by route and status.",
+                        &["method", "route", "status"]);
+    pub static ref REQUEST_DURATION: HistogramVec =
+        HistogramVec::new("pupil_http_request_duration_seconds",
+                          "Time spent handling HTTP requests, by route.",
+                          &["method", "route"],
+                          LATENCY_BUCKETS);
+    pub static ref LOGINS: CounterVec =
+        CounterVec::new("pupil_logins_total",
+                        "Login attempts, by outcome.",
+                        &["outcome"]);
+    pub static ref HASH_DURATION: HistogramVec =
+        HistogramVec::new("pupil_argon2_hash_duration_seconds",
+                          "Time spent hashing passwords with argon2.",
+                          &[],
+                          LATENCY_BUCKETS);
+    pub static ref VERIFY_DURATION: HistogramVec =
+        HistogramVec::new("pupil_argon2_verify_duration_seconds",
+                          "Time spent verifying passwords with argon2.",
+                          &[],
+                          LATENCY_BUCKETS);
+    pub static ref HASH_REJECTED: CounterVec =
+        CounterVec::new("pupil_argon2_rejected_total",
+                        "Password hashing jobs turned away because the queue was full.",
+                        &[]);
+    pub static ref POOL_WAIT: HistogramVec =
+        HistogramVec::new("pupil_db_pool_wait_seconds",
+                          "Time spent waiting for a database connection, by pool.",
+                          &["pool"],
+                          LATENCY_BUCKETS);
+    pub static ref POOL_TIMEOUTS: CounterVec =
+        CounterVec::new("pupil_db_pool_timeouts_total",
+                        "Times no database connection became free in time, by pool.",
+                        &["pool"]);
+}
+
+thread_local! {
+    static STARTED: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Counts and times every request by the route that handled it.
+pub struct RequestMetrics;
+
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, _: &mut Request, _: &Data) {
+        STARTED.with(|started| started.set(Some(Instant::now())));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        // label by route pattern rather than path to keep the series bounded
+        let route = request.route()
+            .map(|route| route.uri.path().to_string())
+            .unwrap_or(String::from("unmatched"));
+        let method = request.method().as_str();
+        let status = response.status().code.to_string();
+
+        REQUESTS.inc(&[method, &route, &status]);
+
+        if let Some(started) = STARTED.with(|started| started.get()) {
+            STARTED.with(|started| started.set(None));
+            REQUEST_DURATION.observe(&[method, &route], seconds(started.elapsed()));
+        }
+    }
+}
+
+/// Request guard for `/metrics`, which says more about the server than anyone
+/// but its operators should see. Scrapers send `METRICS_TOKEN` as a bearer
+/// token; without one configured the route isn't served.
+pub struct MetricsAuth;
+
+impl<'a, 'r> FromRequest<'a, 'r> for MetricsAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<MetricsAuth, ()> {
+        let token = match request.guard::<State<Config>>() {
+            Outcome::Success(config) => {
+                match config.metrics_token {
+                    Some(ref token) => token.clone(),
+                    None => return Outcome::Forward(()),
+                }
+            }
+            _ => return Outcome::Forward(()),
+        };
+
+        let sent = request.headers()
+            .get_one("Authorization")
+            .and_then(|header| {
+                let mut parts = header.trim().splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(sent)) if scheme.eq_ignore_ascii_case("Bearer") => {
+                        Some(sent.trim())
+                    }
+                    _ => None,
+                }
+            });
+
+        let authorized = sent.map_or(false, |sent| {
+            constant_time::verify_slices_are_equal(sent.as_bytes(), token.as_bytes()).is_ok()
+        });
+
+        if authorized {
+            Outcome::Success(MetricsAuth)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+#[get("/metrics")]
+fn metrics(_auth: MetricsAuth, store: Option<State<Store>>) -> Content<String> {
+    let mut out = String::new();
+
+    for counter in &[&*REQUESTS, &*LOGINS, &*HASH_REJECTED, &*POOL_TIMEOUTS] {
+        counter.render(&mut out);
+    }
+    for histogram in &[&*REQUEST_DURATION, &*HASH_DURATION, &*VERIFY_DURATION, &*POOL_WAIT] {
+        histogram.render(&mut out);
+    }
+
+    let pools = store.as_ref().map(|store| store.pool_states()).unwrap_or_default();
+    writeln!(out, "# HELP pupil_db_pool_connections_idle Idle database connections.").unwrap();
+    writeln!(out, "# TYPE pupil_db_pool_connections_idle gauge").unwrap();
+    for &(name, ref state) in &pools {
+        writeln!(out,
+                 "pupil_db_pool_connections_idle{{pool=\"{}\"}} {}",
+                 name,
+                 state.idle_connections)
+            .unwrap();
+    }
+    writeln!(out, "# HELP pupil_db_pool_connections_in_use Database connections in use.").unwrap();
+    writeln!(out, "# TYPE pupil_db_pool_connections_in_use gauge").unwrap();
+    for &(name, ref state) in &pools {
+        writeln!(out,
+                 "pupil_db_pool_connections_in_use{{pool=\"{}\"}} {}",
+                 name,
+                 state.connections - state.idle_connections)
+            .unwrap();
+    }
+
+    Content(ContentType::new("text", "plain; version=0.0.4"), out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rocket;
+    use rocket::testing::MockRequest;
+    use rocket::http::{Method, Header};
+
+    fn metrics_status(token: Option<&str>, sent: Option<&str>) -> Status {
+        let mut config = Config::load_demo().unwrap();
+        config.metrics_token = token.map(String::from);
+
+        let rocket = rocket::ignite().manage(config).mount("/", routes![super::metrics]);
+        let mut req = MockRequest::new(Method::Get, "/metrics");
+        if let Some(sent) = sent {
+            req = req.header(Header::new("Authorization", format!("Bearer {}", sent)));
+        }
+
+        req.dispatch_with(&rocket).status()
+    }
+
+    #[test]
+    fn metrics_need_the_token() {
+        assert_eq!(metrics_status(None, None), Status::NotFound);
+        assert_eq!(metrics_status(None, Some("scrape")), Status::NotFound);
+        assert_eq!(metrics_status(Some("scrape"), None), Status::Unauthorized);
+        assert_eq!(metrics_status(Some("scrape"), Some("guess")), Status::Unauthorized);
+        assert_eq!(metrics_status(Some("scrape"), Some("scrape")), Status::Ok);
+    }
+
+    #[test]
+    fn counter_format() {
+        let counter = CounterVec::new("test_total", "A test counter.", &["outcome"]);
+        counter.inc(&["success"]);
+        counter.inc(&["success"]);
+        counter.inc(&["bad \"password\""]);
+
+        let mut out = String::new();
+        counter.render(&mut out);
+
+        assert_eq!(out,
+                   "# HELP test_total A test counter.\n\
+                    # TYPE test_total counter\n\
+                    test_total{outcome=\"bad \\\"password\\\"\"} 1\n\
+                    test_total{outcome=\"success\"} 2\n");
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = HistogramVec::new("test_seconds", "A test histogram.", &[], &[0.1, 1.0]);
+        histogram.observe(&[], 0.05);
+        histogram.observe(&[], 0.5);
+        histogram.observe(&[], 5.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+
+        assert!(out.contains("test_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+}
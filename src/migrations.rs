@@ -0,0 +1,316 @@
+use diesel;
+use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::expression::dsl::sql;
+use diesel::types::{BigInt, Bool};
+use diesel::pg::PgConnection;
+use diesel::sqlite::SqliteConnection;
+use diesel::result::TransactionError;
+
+use super::model::new_public_id;
+use super::schema::users;
+use super::schema::__diesel_schema_migrations;
+use super::schema::__diesel_schema_migrations::dsl::*;
+
+/// A migration from `migrations/`, compiled into the binary by `build.rs`.
+///
+/// `up.sql` and `down.sql` are run on every database unless the migration
+/// also has an `up.sqlite.sql` or `down.sqlite.sql`, which sqlite runs
+/// instead. Only write those where sqlite can't take the same SQL, like
+/// `serial` columns.
+///
+/// Data SQL can't produce is filled in by `MigrationConnection::backfill`
+/// right after the `up` SQL, so run migrations with `pupil-server migrate` rather
+/// than `diesel migration run`.
+pub struct Migration {
+    pub version: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+    pub sqlite_up: &'static str,
+    pub sqlite_down: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+#[derive(Insertable)]
+#[table_name="__diesel_schema_migrations"]
+struct NewMigration<'a> {
+    version: &'a str,
+}
+
+/// What differs between the databases migrations can run on.
+pub trait MigrationConnection: Connection {
+    fn up_sql(migration: &Migration) -> &'static str;
+
+    fn down_sql(migration: &Migration) -> &'static str;
+
+    fn has_migrations_table(&self) -> QueryResult<bool>;
+
+    fn backfill(&self, migration: &Migration) -> QueryResult<()>;
+
+    fn applied_versions(&self) -> QueryResult<Vec<String>>;
+
+    fn record_applied(&self, migration: &Migration) -> QueryResult<()>;
+
+    fn record_reverted(&self, migration: &Migration) -> QueryResult<()>;
+}
+
+impl MigrationConnection for PgConnection {
+    fn up_sql(migration: &Migration) -> &'static str {
+        migration.up
+    }
+
+    fn down_sql(migration: &Migration) -> &'static str {
+        migration.down
+    }
+
+    fn has_migrations_table(&self) -> QueryResult<bool> {
+        sql::<Bool>("SELECT to_regclass('__diesel_schema_migrations') IS NOT NULL")
+            .get_result(self)
+    }
+
+    fn backfill(&self, migration: &Migration) -> QueryResult<()> {
+        match migration.version {
+            // postgres can't draw random bytes from the OS without pgcrypto,
+            // which most managed databases won't let us create
+            "20170516193027" => {
+                let ids: Vec<i32> = users::table.filter(users::public_id.is_null())
+                    .select(users::id)
+                    .load(self)?;
+                for id in ids {
+                    diesel::update(users::table.find(id))
+                        .set(users::public_id.eq(new_public_id()))
+                        .execute(self)?;
+                }
+                self.batch_execute("alter table users alter column public_id set not null")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn applied_versions(&self) -> QueryResult<Vec<String>> {
+        __diesel_schema_migrations.select(version).order(version).load(self)
+    }
+
+    fn record_applied(&self, migration: &Migration) -> QueryResult<()> {
+        diesel::insert(&NewMigration { version: migration.version })
+            .into(__diesel_schema_migrations::table)
+            .execute(self)
+            .map(|_| ())
+    }
+
+    fn record_reverted(&self, migration: &Migration) -> QueryResult<()> {
+        diesel::delete(__diesel_schema_migrations.filter(version.eq(migration.version)))
+            .execute(self)
+            .map(|_| ())
+    }
+}
+
+impl MigrationConnection for SqliteConnection {
+    fn up_sql(migration: &Migration) -> &'static str {
+        migration.sqlite_up
+    }
+
+    fn down_sql(migration: &Migration) -> &'static str {
+        migration.sqlite_down
+    }
+
+    fn has_migrations_table(&self) -> QueryResult<bool> {
+        let count: i64 = sql::<BigInt>("SELECT count(*) FROM sqlite_master WHERE type = 'table' \
+                                        AND name = '__diesel_schema_migrations'")
+            .get_result(self)?;
+        Ok(count > 0)
+    }
+
+    // the sqlite migrations rebuild their tables, and fill them as they go
+    fn backfill(&self, _migration: &Migration) -> QueryResult<()> {
+        Ok(())
+    }
+
+    fn applied_versions(&self) -> QueryResult<Vec<String>> {
+        __diesel_schema_migrations.select(version).order(version).load(self)
+    }
+
+    fn record_applied(&self, migration: &Migration) -> QueryResult<()> {
+        diesel::insert(&NewMigration { version: migration.version })
+            .into(__diesel_schema_migrations::table)
+            .execute(self)
+            .map(|_| ())
+    }
+
+    fn record_reverted(&self, migration: &Migration) -> QueryResult<()> {
+        diesel::delete(__diesel_schema_migrations.filter(version.eq(migration.version)))
+            .execute(self)
+            .map(|_| ())
+    }
+}
+
+// same table diesel_cli uses, so either tool can be used on a database
+fn setup<C: MigrationConnection>(connection: &C) -> QueryResult<()> {
+    connection.batch_execute("CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+        version VARCHAR(50) PRIMARY KEY NOT NULL,
+        run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )")
+}
+
+fn in_transaction<C, F>(connection: &C, f: F) -> QueryResult<()>
+    where C: MigrationConnection,
+          F: FnOnce() -> QueryResult<()>
+{
+    connection.transaction(f).map_err(|err| match err {
+        TransactionError::CouldntCreateTransaction(err) |
+        TransactionError::UserReturnedError(err) => err,
+    })
+}
+
+pub fn applied<C: MigrationConnection>(connection: &C) -> QueryResult<Vec<String>> {
+    // only looks, so it can back a readiness probe; a database that has never
+    // been migrated simply has nothing applied yet
+    if connection.has_migrations_table()? {
+        connection.applied_versions()
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Every known migration alongside whether it has been applied.
+pub fn status<C: MigrationConnection>(connection: &C)
+                                      -> QueryResult<Vec<(&'static Migration, bool)>> {
+    let applied = applied(connection)?;
+
+    Ok(MIGRATIONS.iter()
+        .map(|migration| (migration, applied.iter().any(|v| v == migration.version)))
+        .collect())
+}
+
+pub fn pending<C: MigrationConnection>(connection: &C) -> QueryResult<Vec<&'static Migration>> {
+    Ok(status(connection)?
+        .into_iter()
+        .filter(|&(_, applied)| !applied)
+        .map(|(migration, _)| migration)
+        .collect())
+}
+
+fn apply<C: MigrationConnection>(connection: &C, migration: &Migration) -> QueryResult<()> {
+    in_transaction(connection, || {
+        connection.batch_execute(C::up_sql(migration))?;
+        connection.backfill(migration)?;
+        connection.record_applied(migration)
+    })
+}
+
+/// Runs every pending migration in order, each in its own transaction.
+pub fn run_pending<C: MigrationConnection>(connection: &C) -> QueryResult<Vec<&'static str>> {
+    setup(connection)?;
+    let mut ran = Vec::new();
+
+    for migration in pending(connection)? {
+        apply(connection, migration)?;
+        ran.push(migration.version);
+    }
+
+    Ok(ran)
+}
+
+/// Reverts the most recently applied migration, if there is one.
+pub fn revert_latest<C: MigrationConnection>(connection: &C)
+                                             -> QueryResult<Option<&'static str>> {
+    let latest = match applied(connection)?.pop() {
+        Some(latest) => latest,
+        None => return Ok(None),
+    };
+
+    let migration = match MIGRATIONS.iter().find(|migration| migration.version == latest) {
+        Some(migration) => migration,
+        None => return Err(diesel::result::Error::NotFound),
+    };
+
+    in_transaction(connection, || {
+        connection.batch_execute(C::down_sql(migration))?;
+        connection.record_reverted(migration)
+    })?;
+
+    Ok(Some(migration.version))
+}
+
+/// Reverts and re-applies the most recent migration.
+pub fn redo<C: MigrationConnection>(connection: &C) -> QueryResult<Option<&'static str>> {
+    match revert_latest(connection)? {
+        Some(reverted) => {
+            let migration = MIGRATIONS.iter()
+                .find(|migration| migration.version == reverted)
+                .unwrap();
+            apply(connection, migration)?;
+            Ok(Some(reverted))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reverts everything, leaving an empty database behind.
+pub fn revert_all<C: MigrationConnection>(connection: &C) -> QueryResult<()> {
+    while let Some(_) = revert_latest(connection)? {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::ops::Deref;
+
+    use super::super::testing::TestDb;
+
+    fn sqlite() -> SqliteConnection {
+        SqliteConnection::establish(":memory:").unwrap()
+    }
+
+    #[test]
+    fn sqlite_up_and_down() {
+        let connection = sqlite();
+
+        let ran = run_pending(&connection).unwrap();
+        assert_eq!(ran.len(), MIGRATIONS.len());
+        assert!(pending(&connection).unwrap().is_empty());
+
+        revert_all(&connection).unwrap();
+        assert_eq!(pending(&connection).unwrap().len(), MIGRATIONS.len());
+
+        // and everything can be applied again on top of what down left
+        run_pending(&connection).unwrap();
+    }
+
+    #[test]
+    fn sqlite_pending_does_not_write() {
+        let connection = sqlite();
+
+        assert_eq!(pending(&connection).unwrap().len(), MIGRATIONS.len());
+        assert!(!connection.has_migrations_table().unwrap());
+    }
+
+    #[test]
+    fn pg_backfills_public_ids() {
+        let db = TestDb::seeded();
+        let connection = db.connection();
+
+        assert_eq!(revert_latest(connection.deref()).unwrap(), Some("20170516193027"));
+        run_pending(connection.deref()).unwrap();
+
+        let ids: Vec<String> = users::table.select(users::public_id)
+            .load(connection.deref())
+            .unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.iter().all(|id| id.len() == 32));
+        assert!(ids[0] != ids[1]);
+    }
+
+    #[test]
+    fn sqlite_redo() {
+        let connection = sqlite();
+        run_pending(&connection).unwrap();
+
+        let latest = MIGRATIONS.last().map(|migration| migration.version);
+        assert_eq!(redo(&connection).unwrap(), latest);
+        assert!(pending(&connection).unwrap().is_empty());
+    }
+}
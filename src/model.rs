@@ -2,47 +2,150 @@ use std::env;
 use std::fmt;
 use std::io;
 use std::cmp::Ordering;
+use std::str::FromStr;
 
 use rocket::request;
 use rocket::outcome::Outcome;
 use rocket::Request;
 use rocket::http::{Cookie, Cookies, Status};
 
-use jwt::{encode, decode, Header, Algorithm, Validation};
+use jwt::{decode, Algorithm, Validation};
 use jwt::errors::Error as JwtError;
 
 use time;
 
+use rand::{thread_rng, Rng};
+
+use log::LogLevel;
+
 use super::error::Error;
+use super::logging;
+use super::auth::{Auth, Scope};
+use super::keys::JwtKeys;
 
 #[derive(Queryable, Clone, Debug)]
 pub struct User {
     pub id: i32,
+    pub public_id: String,
     pub name: String,
     pub email: String,
     pub username: String,
     pub pass: String,
     pub conf: bool,
+    pub role: String,
 }
 
-#[derive(Debug, PartialEq)]
+/// A user as shown outside the server. `id` is their public id; the primary
+/// key never leaves the database.
+#[derive(Serialize, Debug, PartialEq)]
 pub struct SafeUser {
-    pub id: i32,
+    pub id: String,
     pub name: String,
     pub email: String,
     pub username: String,
     pub conf: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Tutor,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Role::Student => "student",
+            Role::Tutor => "tutor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Role, ()> {
+        match s {
+            "student" => Ok(Role::Student),
+            "tutor" => Ok(Role::Tutor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the dashboard needs to know about whoever is signed in.
+#[derive(Serialize)]
+pub struct Me {
+    pub user: SafeUser,
+    pub role: Role,
+    /// Out of the fields the user can fill in themselves.
+    pub profile_completeness: u8,
+    pub missing: Vec<&'static str>,
+}
+
+impl Me {
+    pub fn new(user: User) -> Self {
+        let role = match user.role.parse() {
+            Ok(role) => role,
+            Err(()) => {
+                // shown with the fewest privileges until the row is fixed
+                logging::event(LogLevel::Error,
+                               "unknown role",
+                               &[("role", &user.role), ("user", &user.public_id)]);
+                Role::Student
+            }
+        };
+
+        let mut missing = Vec::new();
+        if user.name.trim().is_empty() {
+            missing.push("name");
+        }
+        if user.email.trim().is_empty() {
+            missing.push("email");
+        }
+        if user.username.trim().is_empty() {
+            missing.push("username");
+        }
+
+        let completeness = (100 * (3 - missing.len()) / 3) as u8;
+
+        Me {
+            user: SafeUser::from(user),
+            role: role,
+            profile_completeness: completeness,
+            missing: missing,
+        }
+    }
+}
+
 use super::schema::users;
 
+fn random_hex(length: usize) -> String {
+    let mut bytes = vec![0u8; length];
+    thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A fresh id to show for a user instead of their primary key, which counts
+/// up and would give away how many users there are.
+pub fn new_public_id() -> String {
+    random_hex(16)
+}
+
 #[derive(Insertable)]
 #[table_name="users"]
 pub struct NewUser<'a> {
+    pub public_id: String,
     pub name: &'a str,
     pub email: &'a str,
     pub username: &'a str,
     pub pass: &'a str,
+    pub conf: bool,
+    pub role: &'a str,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -59,35 +162,103 @@ pub struct Register {
     pub password: String,
 }
 
-impl<'a, 'r> request::FromRequest<'a, 'r> for SafeUser {
-    type Error = Error;
+#[derive(Queryable, Clone, Debug)]
+pub struct AccessToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: String,
+    pub created_at: i64,
+    pub revoked: bool,
+}
 
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<SafeUser, Error> {
-        let mut cookies = request.cookies();
+use super::schema::access_tokens;
 
-        let cookie = match cookies.get("jwt") {
-            Some(cookie) => cookie.to_owned(),
-            None => return Outcome::Failure((Status::NotFound, Error::BadCookie)),
-        };
+#[derive(Insertable)]
+#[table_name="access_tokens"]
+pub struct NewAccessToken<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub token_hash: &'a str,
+    pub scopes: &'a str,
+    pub created_at: i64,
+}
 
-        let mut validation = Validation { iss: Some("pupil".to_string()), ..Default::default() };
+#[derive(Serialize, Deserialize)]
+pub struct CreateToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
 
-        let secret = env::var("JWT_SECRET").expect("JWT_SECRET not set"); // TODO: better errors
+/// An access token as listed back to its owner, without the hash.
+#[derive(Serialize, Debug)]
+pub struct TokenInfo {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: i64,
+    pub revoked: bool,
+}
 
-        match decode::<UserToken>(&cookie.value(), secret.as_bytes(), &validation) {
-            Ok(token) => Outcome::Success(SafeUser::from(token.claims)),
-            Err(_) => {
-                cookies.remove(Cookie::new("jwt", "invalidtoken"));
-                Outcome::Failure((Status::NotFound, Error::BadCookie))
-            }
+impl From<AccessToken> for TokenInfo {
+    fn from(token: AccessToken) -> Self {
+        TokenInfo {
+            id: token.id,
+            name: token.name,
+            scopes: Scope::split(&token.scopes),
+            created_at: token.created_at,
+            revoked: token.revoked,
         }
     }
 }
 
+/// Returned once when a token is created; the plain token is never stored.
+#[derive(Serialize)]
+pub struct CreatedToken {
+    pub info: TokenInfo,
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateUser {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub username: Option<String>,
+}
+
+#[derive(AsChangeset)]
+#[table_name="users"]
+pub struct UserChanges<'a> {
+    pub name: Option<&'a str>,
+    pub email: Option<&'a str>,
+    pub username: Option<&'a str>,
+    pub conf: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteAccount {
+    pub password: String,
+}
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for SafeUser {
+    type Error = Error;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<SafeUser, Error> {
+        request.guard::<Auth>().map(|auth| auth.user)
+    }
+}
+
 impl From<User> for SafeUser {
     fn from(user: User) -> Self {
         SafeUser {
-            id: user.id,
+            id: user.public_id,
             name: user.name,
             email: user.email,
             username: user.username,
@@ -99,7 +270,7 @@ impl From<User> for SafeUser {
 impl From<UserToken> for SafeUser {
     fn from(user: UserToken) -> Self {
         SafeUser {
-            id: user.id,
+            id: user.sub,
             name: user.name,
             email: user.email,
             username: user.username,
@@ -112,8 +283,11 @@ impl From<UserToken> for SafeUser {
 pub struct UserToken {
     pub iat: i64,
     pub exp: i64,
+    pub nbf: i64,
     pub iss: String,
-    pub id: i32,
+    pub aud: String,
+    pub jti: String,
+    pub sub: String,
     pub name: String,
     pub email: String,
     pub username: String,
@@ -122,15 +296,20 @@ pub struct UserToken {
 
 static ONE_MIN: i64 = 60;
 static ISSUER: &'static str = "pupil";
+static AUDIENCE: &'static str = "pupil-api";
 
 impl UserToken {
     pub fn new(user: User) -> Self {
         let now = time::get_time().sec;
+
         UserToken {
             iat: now,
             exp: now + ONE_MIN,
+            nbf: now,
             iss: String::from(ISSUER),
-            id: user.id,
+            aud: String::from(AUDIENCE),
+            jti: random_hex(16),
+            sub: user.public_id,
             name: user.name,
             email: user.email,
             username: user.username,
@@ -138,8 +317,20 @@ impl UserToken {
         }
     }
 
-    pub fn construct_jwt(&self, secret: String) -> String {
-        encode(&Header::default(), self, secret.as_bytes()).unwrap() // TODO error handling
+    pub fn construct_jwt(&self, keys: &JwtKeys) -> Result<String, Error> {
+        Ok(keys.encode(self)?)
+    }
+
+    /// What a token has to pass to be accepted, allowing `leeway` seconds
+    /// of clock skew between whoever issued it and us.
+    pub fn validation(leeway: i64) -> Validation {
+        Validation {
+            leeway: leeway,
+            validate_nbf: true,
+            iss: Some(String::from(ISSUER)),
+            aud: Some(json!(AUDIENCE)),
+            ..Default::default()
+        }
     }
 }
 
@@ -159,21 +350,90 @@ mod tests {
 
         let mut claims = UserToken::new(User {
             id: 1,
+            public_id: String::from("8c6a1e0f4b2d9735e1c0a4f6d8b2e913"),
             name: name,
             email: email,
             username: username,
             pass: pass,
             conf: conf,
+            role: String::from("student"),
         });
 
         claims.iat = issued_at;
         claims.exp = expired;
+        claims.nbf = issued_at;
+        claims.jti = String::from("0123456789abcdef");
+
+        let encoded = "eyJ0eXAiOiJKV1QiLCJhbGciOiJIUzI1NiJ9.eyJpYXQiOjE0OTI5MDc2MzUsImV4cCI6MTQ5M\
+            jkwNzY5NSwibmJmIjoxNDkyOTA3NjM1LCJpc3MiOiJwdXBpbCIsImF1ZCI6InB1cGlsLWFwaSIsImp0aSI6IjA\
+            xMjM0NTY3ODlhYmNkZWYiLCJzdWIiOiI4YzZhMWUwZjRiMmQ5NzM1ZTFjMGE0ZjZkOGIyZTkxMyIsIm5hbWUiO\
+            iJKb2huIFNtaXRoIiwiZW1haWwiOiJqc21pdGhAd2Vic2l0ZS5jb20iLCJ1c2VybmFtZSI6ImpzbWl0aCIsImN\
+            vbmYiOnRydWV9.YS8_9bd2MTZoevVhlJv2J7-NKavC7DUQZfHXw08B84w";
+
+        assert_eq!(claims.construct_jwt(&JwtKeys::shared("secret")).unwrap(), encoded);
+    }
 
-        let encoded = "eyJ0eXAiOiJKV1QiLCJhbGciOiJIUzI1NiJ9.eyJpYXQiOjE0OTI5MDc2MzUsImV4cCI6MTQ5Mj\
-            kwNzY5NSwiaXNzIjoicHVwaWwiLCJpZCI6MSwibmFtZSI6IkpvaG4gU21pdGgiLCJlbWFpbCI6ImpzbWl0aEB3\
-            ZWJzaXRlLmNvbSIsInVzZXJuYW1lIjoianNtaXRoIiwiY29uZiI6dHJ1ZX0.655jzhRXSF05RJyACAWv_tuT9p\
-            MMVyIyMh4Icb6EKOI";
+    #[test]
+    fn unique_jti() {
+        let user = User {
+            id: 1,
+            public_id: new_public_id(),
+            name: String::from("John Smith"),
+            email: String::from("jsmith@website.com"),
+            username: String::from("jsmith"),
+            pass: String::from("hashed_password"),
+            conf: true,
+            role: String::from("student"),
+        };
+
+        let first = UserToken::new(user.clone());
+        let second = UserToken::new(user);
+
+        assert_eq!(first.jti.len(), 32);
+        assert!(first.jti != second.jti);
+    }
+
+    #[test]
+    fn public_ids() {
+        let first = new_public_id();
+
+        assert_eq!(first.len(), 32);
+        assert!(first != new_public_id());
+    }
+
+    #[test]
+    fn me_completeness() {
+        let me = Me::new(User {
+            id: 2,
+            public_id: new_public_id(),
+            name: String::from(" "),
+            email: String::from("jdoe@website.com"),
+            username: String::from("jdoe"),
+            pass: String::from("hashed_password"),
+            conf: false,
+            role: String::from("tutor"),
+        });
+
+        // being unconfirmed isn't something the profile form can fix
+        assert_eq!(me.role, Role::Tutor);
+        assert_eq!(me.profile_completeness, 66);
+        assert_eq!(me.missing, vec!["name"]);
+    }
+
+    #[test]
+    fn me_unknown_role() {
+        let me = Me::new(User {
+            id: 3,
+            public_id: new_public_id(),
+            name: String::from("Jane Doe"),
+            email: String::from("jdoe@website.com"),
+            username: String::from("jdoe"),
+            pass: String::from("hashed_password"),
+            conf: true,
+            role: String::from("superuser"),
+        });
 
-        assert_eq!(claims.construct_jwt(String::from("secret")), encoded);
+        assert_eq!(me.role, Role::Student);
+        assert_eq!(me.profile_completeness, 100);
     }
 }
@@ -2,25 +2,40 @@ use argon2rs::verifier::Encoded;
 use argon2rs::{Argon2, Variant};
 use rand::{thread_rng, Rng};
 
+use log::LogLevel;
+
+use super::logging;
+use super::metrics;
+
 const SALT_LENGTH: usize = 64;
 
 pub fn hash_password(username: &str, pass: &str, secret: &str) -> String {
     // uses recommendations from https://www.owasp.org/index.php/Password_Storage_Cheat_Sheet
 
-    let mut salt_array = [0u8; SALT_LENGTH];
-    thread_rng().fill_bytes(&mut salt_array);
-    let hasher = Argon2::new(10, 1, 4096, Variant::Argon2i).unwrap();
-    String::from_utf8(Encoded::new(hasher,
-                                   pass.as_bytes(),
-                                   &salt_array,
-                                   secret.as_bytes(),
-                                   username.as_bytes())
-            .to_u8())
-        .unwrap() // TODO: error stuff!!!
+    metrics::HASH_DURATION.time(&[], || {
+        let mut salt_array = [0u8; SALT_LENGTH];
+        thread_rng().fill_bytes(&mut salt_array);
+        let hasher = Argon2::new(10, 1, 4096, Variant::Argon2i).unwrap();
+        String::from_utf8(Encoded::new(hasher,
+                                       pass.as_bytes(),
+                                       &salt_array,
+                                       secret.as_bytes(),
+                                       username.as_bytes())
+                .to_u8())
+            .unwrap() // TODO: error stuff!!!
+    })
 }
 
+/// Whether `pass` matches `hash`. A hash that can't be decoded matches
+/// nothing.
 pub fn verify_password(hash: &str, pass: &str) -> bool {
-    Encoded::from_u8(hash.as_bytes()).unwrap().verify(pass.as_bytes())
+    metrics::VERIFY_DURATION.time(&[], || match Encoded::from_u8(hash.as_bytes()) {
+        Ok(encoded) => encoded.verify(pass.as_bytes()),
+        Err(_) => {
+            logging::event(LogLevel::Error, "unreadable password hash", &[]);
+            false
+        }
+    })
 }
 
 #[cfg(test)]
@@ -50,4 +65,10 @@ mod test {
 
         assert!(!same);
     }
+
+    #[test]
+    fn malformed_hash() {
+        assert!(!verify_password("", "password"));
+        assert!(!verify_password("$argon2i$m=4096", "password"));
+    }
 }
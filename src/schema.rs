@@ -1,10 +1,33 @@
 table! {
     users {
         id -> Integer,
+        public_id -> VarChar,
         name -> VarChar,
         email -> VarChar,
         username -> VarChar,
         pass -> VarChar,
         conf -> Bool,
+        role -> VarChar,
+    }
+}
+
+table! {
+    access_tokens {
+        id -> Integer,
+        user_id -> Integer,
+        name -> VarChar,
+        token_hash -> VarChar,
+        scopes -> VarChar,
+        created_at -> BigInt,
+        revoked -> Bool,
+    }
+}
+
+joinable!(access_tokens -> users (user_id));
+
+table! {
+    __diesel_schema_migrations (version) {
+        version -> VarChar,
+        run_on -> Timestamp,
     }
 }
@@ -0,0 +1,348 @@
+//! Security headers for every response, and the endpoint browsers send
+//! Content-Security-Policy violation reports to.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use rocket::{Request, Response, Data};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::response::status;
+
+use log::LogLevel;
+
+use serde_json::{self, Value};
+
+use super::config::Config;
+use super::logging;
+
+pub const CSP_REPORT_PATH: &'static str = "/csp-report";
+
+// violation reports are small; anything bigger isn't one
+const REPORT_LIMIT: u64 = 16 * 1024;
+
+/// A Content-Security-Policy as a list of directives and their sources.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Csp {
+    directives: Vec<(String, Vec<String>)>,
+}
+
+impl Csp {
+    pub fn parse(policy: &str) -> Csp {
+        let directives = policy.split(';')
+            .filter_map(|directive| {
+                let mut words = directive.split_whitespace().map(String::from);
+                words.next().map(|name| (name.to_lowercase(), words.collect()))
+            })
+            .collect();
+
+        Csp { directives: directives }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directives.is_empty()
+    }
+
+    fn sources(&self, directive: &str) -> Option<&Vec<String>> {
+        self.directives.iter().find(|d| d.0 == directive).map(|d| &d.1)
+    }
+
+    fn sources_mut(&mut self, directive: &str) -> Option<&mut Vec<String>> {
+        self.directives.iter_mut().find(|d| d.0 == directive).map(|d| &mut d.1)
+    }
+
+    /// Adds `source` to `directive`. A fetch directive that isn't in the
+    /// policy yet starts from `default-src`'s sources, since adding it at all
+    /// stops `default-src` from applying.
+    pub fn allow(&mut self, directive: &str, source: &str) {
+        let directive = directive.to_lowercase();
+
+        if !self.has(&directive) {
+            let inherited = if directive.ends_with("-src") {
+                self.sources("default-src").cloned().unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            self.directives.push((directive.clone(), inherited));
+        }
+
+        let sources = self.sources_mut(&directive).unwrap();
+        sources.retain(|existing| existing != "'none'");
+        if !sources.iter().any(|existing| existing == source) {
+            sources.push(String::from(source));
+        }
+    }
+
+    pub fn has(&self, directive: &str) -> bool {
+        self.sources(directive).is_some()
+    }
+
+    pub fn render(&self) -> String {
+        let directives: Vec<String> = self.directives
+            .iter()
+            .map(|&(ref name, ref sources)| if sources.is_empty() {
+                name.clone()
+            } else {
+                format!("{} {}", name, sources.join(" "))
+            })
+            .collect();
+        directives.join("; ")
+    }
+}
+
+/// Sets the security headers on every response that doesn't set them
+/// itself. Each is configured in `Config`; an empty value turns it off.
+///
+/// Routes that need more from the policy than the rest of the app, like a
+/// page with an inline script, get it with `allow`, keyed by the route's
+/// mounted path:
+///
+/// ```ignore
+/// SecurityHeaders::new(&config).allow("/dash", "script-src", "'sha256-...'")
+/// ```
+pub struct SecurityHeaders {
+    csp: Csp,
+    report_only: bool,
+    routes: HashMap<String, Csp>,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &Config) -> Self {
+        let mut headers = vec![("X-Content-Type-Options", String::from("nosniff")),
+                               ("X-Frame-Options", String::from("DENY"))];
+        if config.hsts_max_age > 0 {
+            headers.push(("Strict-Transport-Security",
+                          format!("max-age={}; includeSubDomains", config.hsts_max_age)));
+        }
+        if !config.referrer_policy.is_empty() {
+            headers.push(("Referrer-Policy", config.referrer_policy.clone()));
+        }
+        if !config.permissions_policy.is_empty() {
+            headers.push(("Permissions-Policy", config.permissions_policy.clone()));
+        }
+
+        SecurityHeaders {
+            csp: Csp::parse(&config.content_security_policy),
+            report_only: config.csp_report_only,
+            routes: HashMap::new(),
+            headers: headers,
+        }
+    }
+
+    /// Adds `source` to `directive` of the policy sent with responses from
+    /// the route mounted at `path`.
+    pub fn allow(mut self, path: &str, directive: &str, source: &str) -> Self {
+        let csp = self.csp.clone();
+        self.routes.entry(String::from(path)).or_insert(csp).allow(directive, source);
+        self
+    }
+
+    fn policy(&self, request: &Request) -> Option<String> {
+        let csp = request.route()
+            .and_then(|route| self.routes.get(route.uri.path()))
+            .unwrap_or(&self.csp);
+
+        if csp.is_empty() {
+            None
+        } else if self.report_only && !csp.has("report-uri") {
+            let mut csp = csp.clone();
+            csp.allow("report-uri", CSP_REPORT_PATH);
+            Some(csp.render())
+        } else {
+            Some(csp.render())
+        }
+    }
+
+    fn csp_header(&self) -> &'static str {
+        if self.report_only {
+            "Content-Security-Policy-Report-Only"
+        } else {
+            "Content-Security-Policy"
+        }
+    }
+}
+
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let mut headers = self.headers.clone();
+        if let Some(policy) = self.policy(request) {
+            headers.push((self.csp_header(), policy));
+        }
+
+        for (name, value) in headers {
+            if !response.headers().contains(name) {
+                response.set_header(Header::new(name, value));
+            }
+        }
+    }
+}
+
+/// Collects the violation reports browsers send under a report-only policy
+/// (or any policy with a `report-uri` pointing here) and logs them.
+#[post("/csp-report", data = "<data>")]
+fn csp_report(data: Data) -> status::NoContent {
+    let mut body = String::new();
+    if data.open().take(REPORT_LIMIT).read_to_string(&mut body).is_err() {
+        return status::NoContent;
+    }
+
+    if let Ok(report) = serde_json::from_str::<Value>(&body) {
+        let report = &report["csp-report"];
+        let field = |name: &str| report[name].as_str().map(String::from).unwrap_or_default();
+
+        logging::event(LogLevel::Warn,
+                       "csp_violation",
+                       &[("document_uri", &field("document-uri")),
+                         ("violated_directive", &field("violated-directive")),
+                         ("blocked_uri", &field("blocked-uri"))]);
+    }
+
+    status::NoContent
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rocket;
+    use rocket::Rocket;
+    use rocket::testing::MockRequest;
+    use rocket::http::{Method, Status, ContentType};
+
+    #[get("/page")]
+    fn page() -> &'static str {
+        "page"
+    }
+
+    #[get("/framed")]
+    fn framed() -> Response<'static> {
+        Response::build().raw_header("X-Frame-Options", "SAMEORIGIN").finalize()
+    }
+
+    fn headers(policy: &str, report_only: bool) -> SecurityHeaders {
+        SecurityHeaders {
+            csp: Csp::parse(policy),
+            report_only: report_only,
+            routes: HashMap::new(),
+            headers: vec![("X-Frame-Options", String::from("DENY"))],
+        }
+    }
+
+    fn rocket(headers: SecurityHeaders) -> Rocket {
+        rocket::ignite().attach(headers).mount("/", routes![page, framed, csp_report])
+    }
+
+    #[test]
+    fn parse_and_render() {
+        let csp = Csp::parse(" default-src 'self' ;img-src 'self' data:;; \
+                              upgrade-insecure-requests");
+
+        assert_eq!(csp.render(),
+                   "default-src 'self'; img-src 'self' data:; upgrade-insecure-requests");
+        assert!(Csp::parse("").is_empty());
+    }
+
+    #[test]
+    fn allow_starts_from_default_src() {
+        let mut csp = Csp::parse("default-src 'self'; object-src 'none'");
+        csp.allow("script-src", "'sha256-abc'");
+        csp.allow("script-src", "'sha256-abc'");
+        csp.allow("object-src", "'self'");
+
+        assert_eq!(csp.render(),
+                   "default-src 'self'; object-src 'self'; script-src 'self' 'sha256-abc'");
+    }
+
+    #[test]
+    fn sets_headers() {
+        let rocket = rocket(headers("default-src 'self'", false));
+
+        let mut req = MockRequest::new(Method::Get, "/page");
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.headers().get_one("Content-Security-Policy"),
+                   Some("default-src 'self'"));
+        assert_eq!(response.headers().get_one("X-Frame-Options"), Some("DENY"));
+
+        let mut req = MockRequest::new(Method::Get, "/missing");
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.headers().get_one("X-Frame-Options"), Some("DENY"));
+    }
+
+    #[test]
+    fn responses_keep_their_own_headers() {
+        let rocket = rocket(headers("default-src 'self'", false));
+
+        let mut req = MockRequest::new(Method::Get, "/framed");
+        let response = req.dispatch_with(&rocket);
+        let frame_options: Vec<_> = response.headers().get("X-Frame-Options").collect();
+
+        assert_eq!(frame_options, vec!["SAMEORIGIN"]);
+    }
+
+    #[test]
+    fn route_overrides() {
+        let rocket = rocket(headers("default-src 'self'", false)
+            .allow("/page", "script-src", "'sha256-abc'"));
+
+        let mut req = MockRequest::new(Method::Get, "/page");
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.headers().get_one("Content-Security-Policy"),
+                   Some("default-src 'self'; script-src 'self' 'sha256-abc'"));
+
+        let mut req = MockRequest::new(Method::Get, "/framed");
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.headers().get_one("Content-Security-Policy"),
+                   Some("default-src 'self'"));
+    }
+
+    #[test]
+    fn report_only() {
+        let rocket = rocket(headers("default-src 'self'", true));
+
+        let mut req = MockRequest::new(Method::Get, "/page");
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.headers().get_one("Content-Security-Policy"), None);
+        assert_eq!(response.headers().get_one("Content-Security-Policy-Report-Only"),
+                   Some("default-src 'self'; report-uri /csp-report"));
+    }
+
+    #[test]
+    fn empty_policy_is_left_off() {
+        let rocket = rocket(headers("", false));
+
+        let mut req = MockRequest::new(Method::Get, "/page");
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.headers().get_one("Content-Security-Policy"), None);
+    }
+
+    #[test]
+    fn accepts_reports() {
+        let rocket = rocket(headers("default-src 'self'", true));
+        let report = json!({
+            "csp-report": {
+                "document-uri": "https://pupil.io/dash",
+                "violated-directive": "script-src",
+                "blocked-uri": "inline"
+            }
+        });
+
+        let mut req = MockRequest::new(Method::Post, CSP_REPORT_PATH)
+            .header(ContentType::new("application", "csp-report"))
+            .body(report.to_string());
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::NoContent);
+
+        let mut req = MockRequest::new(Method::Post, CSP_REPORT_PATH).body("not json");
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::NoContent);
+    }
+}
@@ -1,36 +1,40 @@
-use std::env;
-use std::io;
 use std::path::{Path, PathBuf};
-use std::ops::Deref;
 
 use rocket::request;
-use rocket::response::{Redirect, NamedFile};
-use rocket::http::{Cookie, Cookies};
-use rocket::State;
+use rocket::response::Redirect;
+use rocket::http::Cookies;
+use rocket::{Request, State};
 use rocket_contrib::{JSON, Value};
 
-use diesel;
-use diesel::prelude::*;
-use diesel::pg::PgConnection;
+use log::LogLevel;
 
-use super::model::{SafeUser, UserToken, Login, User, NewUser, Register};
+use super::assets::{Assets, Asset, AssetRequest};
+use super::model::{SafeUser, Login, NewUser, Register, Role, new_public_id};
 use super::error::{Error, ThresholdKind};
-use super::passwd;
-use super::database::ConnectionPool;
+use super::hasher::Hasher;
+use super::store::Store;
+use super::csrf::{self, SameOrigin};
+use super::config::Config;
+use super::session::AuthCookie;
+use super::keys::JwtKeys;
+use super::logging;
+use super::metrics;
 
 #[get("/")]
-fn index() -> io::Result<NamedFile> {
-    NamedFile::open("static/index.html")
+fn index(assets: State<Assets>, request: AssetRequest) -> Option<Asset> {
+    assets.serve("index.html", &request)
 }
 
 #[get("/dash")]
 fn dash(user: Result<SafeUser, Error>,
-        cookies: Cookies)
-        -> Result<io::Result<NamedFile>, Redirect> {
+        cookies: Cookies,
+        assets: State<Assets>,
+        request: AssetRequest)
+        -> Result<Option<Asset>, Redirect> {
     match user {
         Ok(user) => {
             if user.conf {
-                Ok(NamedFile::open("static/dash.html"))
+                Ok(assets.serve("dash.html", &request))
             } else {
                 Err(Redirect::to("/"))
             }
@@ -40,29 +44,47 @@ fn dash(user: Result<SafeUser, Error>,
 }
 
 #[post("/login", format = "application/json", data = "<data>")]
-fn login(mut cookies: Cookies,
+fn login(_origin: SameOrigin,
+         mut cookies: Cookies,
          data: JSON<Login>,
-         pool: State<ConnectionPool>)
+         store: State<Store>,
+         hasher: State<Hasher>,
+         config: State<Config>,
+         keys: State<JwtKeys>)
          -> Result<JSON<String>, Error> {
-    use super::schema::users;
-
     let data = data.into_inner();
 
-    let connection = pool.0.get()?;
-
-    let user: User = users::table.filter(users::username.eq(&data.username))
-        .first::<User>(connection.deref())?;
+    let user = store.users()
+        .find_by_username(&data.username)
+        .map_err(|err| {
+            if let Error::BadUserOrPass = err {
+                // whatever was typed in, which is sometimes a password
+                logging::event(LogLevel::Info, "login", &[("outcome", "unknown_user")]);
+                metrics::LOGINS.inc(&["bad_user_or_pass"]);
+            }
+            err
+        })?;
 
-    if passwd::verify_password(user.pass.as_str(), data.password.as_str()) {
+    if hasher.verify_password(user.pass.as_str(), data.password.as_str())? {
         if user.conf {
-            let token = UserToken::new(user.clone())
-                .construct_jwt(env::var("JWT_SECRET").expect("JWT_SECRET not set"));
-            cookies.add(Cookie::new("jwt", token));
+            logging::event(LogLevel::Info,
+                           "login",
+                           &[("outcome", "success"), ("username", &user.username)]);
+            metrics::LOGINS.inc(&["success"]);
+            AuthCookie::new(&config).issue(&mut cookies, user, &keys)?;
             Ok(JSON(String::from("dash")))
         } else {
+            logging::event(LogLevel::Info,
+                           "login",
+                           &[("outcome", "not_confirmed"), ("username", &user.username)]);
+            metrics::LOGINS.inc(&["not_confirmed"]);
             Err(Error::NotConfirmed(ThresholdKind::Login))
         }
     } else {
+        logging::event(LogLevel::Info,
+                       "login",
+                       &[("outcome", "bad_password"), ("username", &user.username)]);
+        metrics::LOGINS.inc(&["bad_user_or_pass"]);
         Err(Error::BadUserOrPass)
     }
 
@@ -70,55 +92,72 @@ fn login(mut cookies: Cookies,
 }
 
 #[post("/register", format = "application/json", data = "<data>")]
-fn register(data: JSON<Register>, pool: State<ConnectionPool>) -> Result<JSON<String>, Error> {
-    use super::schema::users;
-
-    let connection = pool.0.get()?;
+fn register(_origin: SameOrigin,
+            data: JSON<Register>,
+            store: State<Store>,
+            hasher: State<Hasher>,
+            config: State<Config>)
+            -> Result<JSON<String>, Error> {
     let data = data.into_inner();
 
-    let secret = env::var("HASH_SECRET").expect("HASH_SECRET not set");
-    let secure_pass = passwd::hash_password(data.username.as_str(),
-                                            data.password.as_str(),
-                                            secret.as_str());
+    let secure_pass = hasher.hash_password(data.username.as_str(),
+                                           data.password.as_str(),
+                                           config.hash_secret.as_str())?;
 
     let new_user = NewUser {
+        public_id: new_public_id(),
         name: data.name.as_str(),
         email: data.email.as_str(),
         username: data.username.as_str(),
         pass: secure_pass.as_str(),
+        conf: false,
+        role: Role::Student.as_str(),
     };
 
-    diesel::insert(&new_user).into(users::table)
-        .execute(connection.deref())?;
+    store.users().create(&new_user)?;
 
     Err(Error::NotConfirmed(ThresholdKind::Register))
 
     // TODO: send confirmation email
 }
 
-#[get("/logout")]
-fn logout(mut cookies: Cookies) -> Redirect {
-    cookies.remove(Cookie::new("jwt", "invalidtoken"));
+#[post("/logout")]
+fn logout(_origin: SameOrigin, mut cookies: Cookies, config: State<Config>) -> Redirect {
+    AuthCookie::new(&config).remove(&mut cookies);
     Redirect::to("/")
 }
 
 #[get("/favicon.ico")]
-fn favicon() -> io::Result<NamedFile> {
-    NamedFile::open("static/favicon.ico")
+fn favicon(assets: State<Assets>, request: AssetRequest) -> Option<Asset> {
+    assets.serve("favicon.ico", &request)
 }
 
 #[get("/static/<file..>")]
-fn file(file: PathBuf) -> Option<NamedFile> {
-    NamedFile::open(Path::new("./static/static/").join(file)).ok()
+fn file(file: PathBuf, assets: State<Assets>, request: AssetRequest) -> Option<Asset> {
+    assets.serve(Path::new("static").join(file), &request)
+}
+
+// catchers don't get to see why a guard failed, so the origin check is run
+// again to tell a blocked cross-site request from any other refusal
+#[error(403)]
+fn forbidden(request: &Request) -> Error {
+    match csrf::check(request) {
+        Err(err) => err,
+        Ok(()) => Error::Forbidden,
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use super::super::database::ConnectionPool;
-    use super::super::model::{Login, NewUser};
+    use super::super::config::Config;
+    use super::super::model::{Login, User, UserToken};
     use super::super::error::{Error, ThresholdKind};
     use super::super::schema::users;
+    use super::super::store::{MemoryStore, DEMO_PASSWORD};
+    use super::super::testing::{TestDb, error_message};
+
+    use std::ops::Deref;
 
     use std::path::PathBuf;
     use std::io::prelude::*;
@@ -128,10 +167,10 @@ mod test {
 
     use rocket;
     use rocket::testing::MockRequest;
-    use rocket::http::{Status, Method, Cookie, ContentType};
+    use rocket::response::Failure;
+    use rocket::http::{Status, Method, Cookie, ContentType, Header};
 
-    use diesel::migrations;
-    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
 
     use serde_json;
 
@@ -143,47 +182,11 @@ mod test {
         path
     }
 
-    fn run_migrations() {
-        let connection = PgConnection::establish(env::var("DATABASE_URL").unwrap().as_str())
-            .unwrap();
-
-        connection.execute("create table users (
-          id serial primary key,
-          name varchar not null,
-          email varchar not null unique,
-          username varchar not null unique,
-          pass varchar not null,
-          conf boolean not null default 'f'
-        )")
-            .unwrap();
-
-        connection.execute("\
-            INSERT INTO users (name, email, username, pass, conf)
-                VALUES ('John Smith', 'jsmith@website.com', 'jsmith', '$argon2i$m=4096,t=10,p=1,\
-                    keyid=c2VjcmV0,data=anNtaXRo$elvekjRXU/2NqdkYTxb8T155N1QiXMAYhTWdX+vtyOm+kM81W\
-                    27CsdOsMabqYkYaM3qKdhOKZuxS0v8bZojvLg$Mqnr5Isv3B3LzWU8WjNFDSklhOf8sANtS41PHBVJ\
-                    tFk', true)")
-            .unwrap();
-
-        connection.execute("\
-            INSERT INTO users (name, email, username, pass, conf)
-                VALUES ('Jane Doe', 'jdoe@website.com', 'jdoe', '$argon2i$m=4096,t=10,p=1,\
-                    keyid=c2VjcmV0,data=anNtaXRo$elvekjRXU/2NqdkYTxb8T155N1QiXMAYhTWdX+vtyOm+kM81W\
-                    27CsdOsMabqYkYaM3qKdhOKZuxS0v8bZojvLg$Mqnr5Isv3B3LzWU8WjNFDSklhOf8sANtS41PHBVJ\
-                    tFk', false)")
-            .unwrap();
-    }
-
-    fn revert_migrations() {
-        let connection = PgConnection::establish(env::var("DATABASE_URL").unwrap().as_str())
-            .unwrap();
-
-        connection.execute("drop table users").unwrap();
-    }
-
     #[test]
     fn index() {
-        let rocket = rocket::ignite().mount("/", routes![super::index]);
+        let rocket = rocket::ignite()
+            .manage(Assets::with_root("static"))
+            .mount("/", routes![super::index]);
         let mut req = MockRequest::new(Method::Get, "/");
         let mut response = req.dispatch_with(&rocket);
 
@@ -213,16 +216,24 @@ mod test {
 
         let mut claims = UserToken::new(User {
             id: 1,
+            public_id: new_public_id(),
             name: name,
             email: email,
             username: username,
             pass: pass,
             conf: conf,
+            role: String::from("student"),
         });
 
-        let token = claims.construct_jwt(env::var("JWT_SECRET").unwrap());
+        let config = Config::load().unwrap();
+        let keys = JwtKeys::new(&config).unwrap();
+        let token = claims.construct_jwt(&keys).unwrap();
 
-        let rocket = rocket::ignite().mount("/", routes![super::dash]);
+        let rocket = rocket::ignite()
+            .manage(config)
+            .manage(keys)
+            .manage(Assets::with_root("static"))
+            .mount("/", routes![super::dash]);
         let mut req = MockRequest::new(Method::Get, "/dash").cookie(Cookie::new("jwt", token));
         let mut response = req.dispatch_with(&rocket);
 
@@ -252,16 +263,24 @@ mod test {
 
         let mut claims = UserToken::new(User {
             id: 1,
+            public_id: new_public_id(),
             name: name,
             email: email,
             username: username,
             pass: pass,
             conf: conf,
+            role: String::from("student"),
         });
 
-        let token = claims.construct_jwt(env::var("JWT_SECRET").unwrap());
+        let config = Config::load().unwrap();
+        let keys = JwtKeys::new(&config).unwrap();
+        let token = claims.construct_jwt(&keys).unwrap();
 
-        let rocket = rocket::ignite().mount("/", routes![super::dash]);
+        let rocket = rocket::ignite()
+            .manage(config)
+            .manage(keys)
+            .manage(Assets::with_root("static"))
+            .mount("/", routes![super::dash]);
         let mut req = MockRequest::new(Method::Get, "/dash").cookie(Cookie::new("jwt", token));
         let mut response = req.dispatch_with(&rocket);
 
@@ -270,7 +289,10 @@ mod test {
 
     #[test]
     fn dash_unauthed() {
-        let rocket = rocket::ignite().mount("/", routes![super::dash]);
+        let rocket = rocket::ignite()
+            .manage(Config::load().unwrap())
+            .manage(Assets::with_root("static"))
+            .mount("/", routes![super::dash]);
         let mut req = MockRequest::new(Method::Get, "/dash");
         let mut response = req.dispatch_with(&rocket);
 
@@ -279,18 +301,14 @@ mod test {
 
     #[test]
     fn login_confirmed() {
-        dotenv().ok();
-
-        run_migrations();
+        let db = TestDb::seeded();
 
         let login = Login {
             username: String::from("jsmith"),
             password: String::from("test"),
         };
 
-        let rocket = rocket::ignite()
-            .manage(ConnectionPool::new())
-            .mount("/", routes![super::login]);
+        let rocket = db.rocket().mount("/", routes![super::login]);
         let mut req = MockRequest::new(Method::Post, "/login")
             .header(ContentType::JSON)
             .body(serde_json::to_string(&login).unwrap());
@@ -298,26 +316,46 @@ mod test {
 
         let body = response.body().and_then(|b| b.into_string());
 
-        revert_migrations();
-
         assert_eq!(response.status(), Status::Ok);
         assert_eq!(body, Some(serde_json::to_string(&"dash").unwrap()));
     }
 
     #[test]
-    fn login_not_confirmed() {
-        dotenv().ok();
+    fn login_without_database() {
+        let config = Config::load_demo().unwrap();
 
-        run_migrations();
+        let login = Login {
+            username: String::from("tutor"),
+            password: String::from(DEMO_PASSWORD),
+        };
+
+        let rocket = rocket::ignite()
+            .manage(Store::new(MemoryStore::demo(&config.hash_secret)))
+            .manage(Hasher::with_capacity(1, 8))
+            .manage(JwtKeys::new(&config).unwrap())
+            .manage(config)
+            .mount("/", routes![super::login]);
+        let mut req = MockRequest::new(Method::Post, "/login")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&login).unwrap());
+        let response = req.dispatch_with(&rocket);
+
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers()
+            .get("Set-Cookie")
+            .any(|c| c.starts_with("jwt=") && c.contains("HttpOnly")));
+    }
+
+    #[test]
+    fn login_not_confirmed() {
+        let db = TestDb::seeded();
 
         let login = Login {
             username: String::from("jdoe"),
             password: String::from("test"),
         };
 
-        let rocket = rocket::ignite()
-            .manage(ConnectionPool::new())
-            .mount("/", routes![super::login]);
+        let rocket = db.rocket().mount("/", routes![super::login]);
         let mut req = MockRequest::new(Method::Post, "/login")
             .header(ContentType::JSON)
             .body(serde_json::to_string(&login).unwrap());
@@ -325,20 +363,14 @@ mod test {
 
         let body = response.body().and_then(|b| b.into_string());
 
-        revert_migrations();
-
         assert_eq!(response.status(), Status::BadRequest);
-        assert_eq!(body,
-                   Some(serde_json::to_string(Error::NotConfirmed(ThresholdKind::Login)
-                           .description())
-                       .unwrap()));
+        assert_eq!(error_message(body),
+                   Some(Error::NotConfirmed(ThresholdKind::Login).description().to_owned()));
     }
 
     #[test]
     fn register_new() {
-        dotenv().ok();
-
-        run_migrations();
+        let db = TestDb::seeded();
 
         let new_name = "Diff Perse";
         let new_email = "dperse@website.com";
@@ -351,9 +383,7 @@ mod test {
             password: String::from("bad_pass"),
         };
 
-        let rocket = rocket::ignite()
-            .manage(ConnectionPool::new())
-            .mount("/", routes![super::register]);
+        let rocket = db.rocket().mount("/", routes![super::register]);
         let mut req = MockRequest::new(Method::Post, "/register")
             .header(ContentType::JSON)
             .body(serde_json::to_string(&register).unwrap());
@@ -361,52 +391,47 @@ mod test {
 
         let body = response.body().and_then(|b| b.into_string());
 
-        let connection = PgConnection::establish(env::var("DATABASE_URL").unwrap().as_str())
+        let actual_users: Vec<User> = users::table.order(users::id)
+            .load(db.connection().deref())
             .unwrap();
-
-        let actual_users: Vec<User> = users::table.load(&connection).unwrap();
+        let ids: Vec<String> = actual_users.iter().map(|user| user.public_id.clone()).collect();
         let mut actual_safe_users: Vec<SafeUser> = Vec::with_capacity(3);
         for user in actual_users {
             actual_safe_users.push(SafeUser::from(user));
         }
 
         let expected_safe_users = vec![SafeUser {
-                                           id: 1,
+                                           id: ids[0].clone(),
                                            name: String::from("John Smith"),
                                            email: String::from("jsmith@website.com"),
                                            username: String::from("jsmith"),
                                            conf: true,
                                        },
                                        SafeUser {
-                                           id: 2,
+                                           id: ids[1].clone(),
                                            name: String::from("Jane Doe"),
                                            email: String::from("jdoe@website.com"),
                                            username: String::from("jdoe"),
                                            conf: false,
                                        },
                                        SafeUser {
-                                           id: 3,
+                                           id: ids[2].clone(),
                                            name: String::from(new_name),
                                            email: String::from(new_email),
                                            username: String::from(new_username),
                                            conf: false,
                                        }];
 
-        revert_migrations();
-
         assert_eq!(response.status(), Status::BadRequest);
-        assert_eq!(body,
-                   Some(serde_json::to_string(Error::NotConfirmed(ThresholdKind::Register)
-                           .description())
-                       .unwrap()));
+        assert_eq!(error_message(body),
+                   Some(Error::NotConfirmed(ThresholdKind::Register).description().to_owned()));
         assert_eq!(expected_safe_users, actual_safe_users);
+        assert_eq!(ids[2].len(), 32);
     }
 
     #[test]
     fn register_email_existing() {
-        dotenv().ok();
-
-        run_migrations();
+        let db = TestDb::seeded();
 
         let register = Register {
             name: String::from("Jane Doe"),
@@ -415,9 +440,7 @@ mod test {
             password: String::from("bad_pass"),
         };
 
-        let rocket = rocket::ignite()
-            .manage(ConnectionPool::new())
-            .mount("/", routes![super::register]);
+        let rocket = db.rocket().mount("/", routes![super::register]);
         let mut req = MockRequest::new(Method::Post, "/register")
             .header(ContentType::JSON)
             .body(serde_json::to_string(&register).unwrap());
@@ -425,18 +448,14 @@ mod test {
 
         let body = response.body().and_then(|b| b.into_string());
 
-        revert_migrations();
-
         assert_eq!(response.status(), Status::BadRequest);
-        assert_eq!(body,
-                   Some(serde_json::to_string(Error::EmailTaken.description()).unwrap()));
+        assert_eq!(error_message(body),
+                   Some(Error::EmailTaken.description().to_owned()));
     }
 
     #[test]
     fn register_username_existing() {
-        dotenv().ok();
-
-        run_migrations();
+        let db = TestDb::seeded();
 
         let register = Register {
             name: String::from("Jane Doe"),
@@ -445,9 +464,7 @@ mod test {
             password: String::from("bad_pass"),
         };
 
-        let rocket = rocket::ignite()
-            .manage(ConnectionPool::new())
-            .mount("/", routes![super::register]);
+        let rocket = db.rocket().mount("/", routes![super::register]);
         let mut req = MockRequest::new(Method::Post, "/register")
             .header(ContentType::JSON)
             .body(serde_json::to_string(&register).unwrap());
@@ -455,16 +472,56 @@ mod test {
 
         let body = response.body().and_then(|b| b.into_string());
 
-        revert_migrations();
-
         assert_eq!(response.status(), Status::BadRequest);
-        assert_eq!(body,
-                   Some(serde_json::to_string(Error::UserTaken.description()).unwrap()));
+        assert_eq!(error_message(body),
+                   Some(Error::UserTaken.description().to_owned()));
+    }
+
+    #[test]
+    fn logout_cross_origin() {
+        let rocket = rocket::ignite()
+            .mount("/", routes![super::logout])
+            .catch(errors![super::forbidden]);
+        let mut req = MockRequest::new(Method::Post, "/logout")
+            .header(Header::new("Host", "pupil.io"))
+            .header(Header::new("Origin", "https://evil.example"))
+            .cookie(Cookie::new("jwt", "sometoken"));
+        let mut response = req.dispatch_with(&rocket);
+
+        let body = response.body().and_then(|b| b.into_string());
+
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(error_message(body),
+                   Some(Error::CrossOrigin.description().to_owned()));
+    }
+
+    #[get("/denied")]
+    fn denied() -> Failure {
+        Failure(Status::Forbidden)
+    }
+
+    #[test]
+    fn forbidden_same_origin() {
+        let rocket = rocket::ignite()
+            .mount("/", routes![denied])
+            .catch(errors![super::forbidden]);
+        let mut req = MockRequest::new(Method::Get, "/denied")
+            .header(Header::new("Host", "pupil.io"))
+            .header(Header::new("Origin", "https://evil.example"));
+        let mut response = req.dispatch_with(&rocket);
+
+        let body = response.body().and_then(|b| b.into_string());
+
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(error_message(body),
+                   Some(Error::Forbidden.description().to_owned()));
     }
 
     #[test]
     fn favicon() {
-        let rocket = rocket::ignite().mount("/", routes![super::favicon]);
+        let rocket = rocket::ignite()
+            .manage(Assets::with_root("static"))
+            .mount("/", routes![super::favicon]);
         let mut req = MockRequest::new(Method::Get, "/favicon.ico");
         let mut response = req.dispatch_with(&rocket);
 
@@ -0,0 +1,245 @@
+//! The cookie a signed in browser carries its jwt in. It is always
+//! `HttpOnly` and `SameSite=Lax`, and lives exactly as long as the jwt. With
+//! `COOKIE_SECURE` it is also `Secure` and named with the `__Host-` prefix,
+//! and with `COOKIE_SECRET` its contents are encrypted, so the name and email
+//! in the jwt can't be read off the browser.
+
+use std::fmt::Write;
+use std::str;
+
+use rocket::http::{Cookie, Cookies, SameSite};
+
+use ring::{aead, digest, hkdf, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use time::Duration;
+
+use super::config::Config;
+use super::error::Error;
+use super::keys::JwtKeys;
+use super::model::{User, UserToken};
+
+const NAME: &'static str = "jwt";
+const SECURE_NAME: &'static str = "__Host-jwt";
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32;
+// binds the derived key to this one use, so the same secret used elsewhere
+// doesn't give the same key
+const KEY_CONTEXT: &'static [u8] = b"pupil auth cookie encryption v1";
+
+/// How auth cookies are made and read, as configured.
+pub struct AuthCookie {
+    secure: bool,
+    key: Option<Vec<u8>>,
+}
+
+impl AuthCookie {
+    pub fn new(config: &Config) -> Self {
+        AuthCookie {
+            secure: config.cookie_secure,
+            key: config.cookie_secret.as_ref().map(|secret| cookie_key(secret)),
+        }
+    }
+
+    /// `__Host-` cookies are only accepted over https, from the host itself,
+    /// for the whole site.
+    pub fn name(&self) -> &'static str {
+        if self.secure { SECURE_NAME } else { NAME }
+    }
+
+    fn build(&self, value: String, max_age: i64) -> Cookie<'static> {
+        Cookie::build(self.name(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::seconds(max_age))
+            .finish()
+    }
+
+    /// The cookie's value for `jwt`, encrypted when there is a key.
+    pub fn value(&self, jwt: &str) -> String {
+        match self.key {
+            Some(ref key) => seal(key, jwt),
+            None => String::from(jwt),
+        }
+    }
+
+    /// Signs `user` in with a fresh jwt.
+    pub fn issue(&self, cookies: &mut Cookies, user: User, keys: &JwtKeys) -> Result<(), Error> {
+        let claims = UserToken::new(user);
+        let jwt = claims.construct_jwt(keys)?;
+        cookies.add(self.build(self.value(&jwt), claims.exp - claims.iat));
+        Ok(())
+    }
+
+    pub fn remove(&self, cookies: &mut Cookies) {
+        cookies.remove(self.build(String::new(), 0));
+    }
+
+    /// The jwt in the request's cookie, if there is one and it decrypts. A
+    /// cookie that doesn't is removed, so the browser stops sending it.
+    pub fn jwt(&self, cookies: &mut Cookies) -> Option<String> {
+        let value = match cookies.get(self.name()) {
+            Some(cookie) => String::from(cookie.value()),
+            None => return None,
+        };
+
+        let key = match self.key {
+            Some(ref key) => key,
+            None => return Some(value),
+        };
+
+        let jwt = open(key, &value);
+        if jwt.is_none() {
+            self.remove(cookies);
+        }
+        jwt
+    }
+
+    /// The cookie as a browser would send it back after `issue`.
+    #[cfg(test)]
+    pub fn sent(&self, jwt: &str) -> Cookie<'static> {
+        Cookie::new(self.name(), self.value(jwt))
+    }
+}
+
+/// An AES-256 key from `COOKIE_SECRET` with HKDF-SHA256, so a secret of any
+/// length or shape makes a uniformly random key.
+fn cookie_key(secret: &str) -> Vec<u8> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, &[]);
+    let mut key = vec![0; KEY_LENGTH];
+    hkdf::extract_and_expand(&salt, secret.as_bytes(), KEY_CONTEXT, &mut key);
+    key
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+/// AES-256-GCM under a random nonce, as hex of the nonce followed by the
+/// ciphertext and tag.
+fn seal(key: &[u8], plaintext: &str) -> String {
+    let algorithm = &aead::AES_256_GCM;
+    let key = aead::SealingKey::new(algorithm, key).unwrap();
+
+    // a repeated nonce under the same key gives away both plaintexts, so it
+    // comes straight from the OS
+    let mut nonce = [0u8; NONCE_LENGTH];
+    SystemRandom::new().fill(&mut nonce).expect("The OS could not provide random bytes.");
+
+    let mut in_out = plaintext.as_bytes().to_vec();
+    in_out.extend(vec![0; algorithm.tag_len()]);
+    let length = aead::seal_in_place(&key, &nonce, &mut in_out, algorithm.tag_len(), &[]).unwrap();
+
+    let mut sealed = to_hex(&nonce);
+    sealed.push_str(&to_hex(&in_out[..length]));
+    sealed
+}
+
+fn open(key: &[u8], sealed: &str) -> Option<String> {
+    let mut bytes = match from_hex(sealed) {
+        Some(bytes) => bytes,
+        None => return None,
+    };
+    if bytes.len() <= NONCE_LENGTH {
+        return None;
+    }
+
+    let key = aead::OpeningKey::new(&aead::AES_256_GCM, key).unwrap();
+    let (nonce, in_out) = bytes.split_at_mut(NONCE_LENGTH);
+
+    aead::open_in_place(&key, nonce, 0, in_out, &[])
+        .ok()
+        .and_then(|plaintext| String::from_utf8(plaintext.to_vec()).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn auth_cookie(secure: bool, secret: Option<&str>) -> AuthCookie {
+        AuthCookie {
+            secure: secure,
+            key: secret.map(cookie_key),
+        }
+    }
+
+    #[test]
+    fn hardened_attributes() {
+        let cookie = auth_cookie(true, None).build(String::from("token"), 60).to_string();
+
+        assert!(cookie.starts_with("__Host-jwt=token"));
+        for attribute in &["HttpOnly", "Secure", "SameSite=Lax", "Path=/", "Max-Age=60"] {
+            assert!(cookie.contains(attribute), "{} missing from {}", attribute, cookie);
+        }
+        assert!(!cookie.contains("Domain"));
+
+        let cookie = auth_cookie(false, None).build(String::from("token"), 60).to_string();
+        assert!(cookie.starts_with("jwt=token"));
+        assert!(!cookie.contains("Secure"));
+    }
+
+    #[test]
+    fn encrypts_contents() {
+        let cookie = auth_cookie(false, Some("cookie secret"));
+
+        let first = cookie.value("header.claims.signature");
+        let second = cookie.value("header.claims.signature");
+
+        assert!(!first.contains("claims"));
+        assert!(first != second);
+        assert_eq!(open(cookie.key.as_ref().unwrap(), &first),
+                   Some(String::from("header.claims.signature")));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let cookie = auth_cookie(false, Some("cookie secret"));
+        let other = auth_cookie(false, Some("another secret"));
+        let key = cookie.key.as_ref().unwrap();
+
+        let mut sealed = cookie.value("header.claims.signature");
+        let last = if sealed.ends_with('0') { "1" } else { "0" };
+        let length = sealed.len();
+        sealed.truncate(length - 1);
+        sealed.push_str(last);
+
+        assert_eq!(open(key, &sealed), None);
+        assert_eq!(open(other.key.as_ref().unwrap(), &cookie.value("jwt")), None);
+        assert_eq!(open(key, "header.claims.signature"), None);
+        assert_eq!(open(key, "abc"), None);
+    }
+
+    #[test]
+    fn key_is_derived() {
+        let key = cookie_key("cookie secret");
+
+        assert_eq!(key.len(), KEY_LENGTH);
+        assert_eq!(key, cookie_key("cookie secret"));
+        assert!(key != cookie_key("another secret"));
+        assert!(key != digest::digest(&digest::SHA256, b"cookie secret").as_ref().to_vec());
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("0g"), None);
+    }
+}
@@ -0,0 +1,361 @@
+use std::sync::Mutex;
+
+use super::{Storage, UserStore, AccessTokenStore};
+use super::super::error::Error;
+use super::super::model::{User, NewUser, UserChanges, AccessToken, NewAccessToken, Role,
+                          new_public_id};
+use super::super::passwd;
+
+pub const DEMO_PASSWORD: &'static str = "demo";
+
+#[derive(Default)]
+struct Tables {
+    users: Vec<User>,
+    access_tokens: Vec<AccessToken>,
+    // ids count up from 1 and are never reused, like a serial column
+    last_user_id: i32,
+    last_access_token_id: i32,
+}
+
+impl Tables {
+    fn check_unique(&self, id: Option<i32>, email: &str, username: &str) -> Result<(), Error> {
+        let others = self.users.iter().filter(|user| Some(user.id) != id);
+
+        for user in others {
+            if user.email == email {
+                return Err(Error::EmailTaken);
+            }
+            if user.username == username {
+                return Err(Error::UserTaken);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn user_mut(&mut self, id: i32) -> Result<&mut User, Error> {
+        self.users.iter_mut().find(|user| user.id == id).ok_or(Error::BadUserOrPass)
+    }
+}
+
+/// Keeps everything in memory, gone when the process exits.
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    /// A store holding a confirmed student, tutor and admin, each named
+    /// after their role and with the password `demo`.
+    pub fn demo(hash_secret: &str) -> Self {
+        let store = MemoryStore::new();
+
+        for &role in &[Role::Student, Role::Tutor, Role::Admin] {
+            let username = role.as_str();
+            let pass = passwd::hash_password(username, DEMO_PASSWORD, hash_secret);
+            let email = format!("{}@demo.pupil.io", username);
+
+            store.users()
+                .create(&NewUser {
+                    public_id: new_public_id(),
+                    name: username,
+                    email: &email,
+                    username: username,
+                    pass: &pass,
+                    conf: true,
+                    role: role.as_str(),
+                })
+                .unwrap();
+        }
+
+        store
+    }
+
+}
+
+impl UserStore for MemoryStore {
+    fn find(&self, public_id: &str) -> Result<User, Error> {
+        self.tables
+            .lock()
+            .unwrap()
+            .users
+            .iter()
+            .find(|user| user.public_id == public_id)
+            .cloned()
+            .ok_or(Error::BadUserOrPass)
+    }
+
+    fn find_by_username(&self, username: &str) -> Result<User, Error> {
+        self.tables
+            .lock()
+            .unwrap()
+            .users
+            .iter()
+            .find(|user| user.username == username)
+            .cloned()
+            .ok_or(Error::BadUserOrPass)
+    }
+
+    fn create(&self, user: &NewUser) -> Result<User, Error> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.check_unique(None, user.email, user.username)?;
+
+        tables.last_user_id += 1;
+        let created = User {
+            id: tables.last_user_id,
+            public_id: user.public_id.clone(),
+            name: String::from(user.name),
+            email: String::from(user.email),
+            username: String::from(user.username),
+            pass: String::from(user.pass),
+            conf: user.conf,
+            role: String::from(user.role),
+        };
+        tables.users.push(created.clone());
+
+        Ok(created)
+    }
+
+    fn update(&self, id: i32, changes: &UserChanges) -> Result<User, Error> {
+        let mut tables = self.tables.lock().unwrap();
+
+        let (email, username) = {
+            let current = tables.user_mut(id)?;
+            (String::from(changes.email.unwrap_or(&current.email)),
+             String::from(changes.username.unwrap_or(&current.username)))
+        };
+        tables.check_unique(Some(id), &email, &username)?;
+
+        let user = tables.user_mut(id)?;
+        if let Some(name) = changes.name {
+            user.name = String::from(name);
+        }
+        user.email = email;
+        user.username = username;
+        if let Some(conf) = changes.conf {
+            user.conf = conf;
+        }
+
+        Ok(user.clone())
+    }
+
+    fn set_password(&self, id: i32, pass: &str) -> Result<(), Error> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.user_mut(id)?.pass = String::from(pass);
+        Ok(())
+    }
+
+    fn set_role(&self, id: i32, role: &str) -> Result<(), Error> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.user_mut(id)?.role = String::from(role);
+        Ok(())
+    }
+
+    fn list(&self, unconfirmed_only: bool) -> Result<Vec<User>, Error> {
+        Ok(self.tables
+            .lock()
+            .unwrap()
+            .users
+            .iter()
+            .filter(|user| !unconfirmed_only || !user.conf)
+            .cloned()
+            .collect())
+    }
+
+    fn delete(&self, id: i32) -> Result<(), Error> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.users.retain(|user| user.id != id);
+        tables.access_tokens.retain(|token| token.user_id != id);
+        Ok(())
+    }
+}
+
+impl AccessTokenStore for MemoryStore {
+    fn list(&self, user_id: i32) -> Result<Vec<AccessToken>, Error> {
+        Ok(self.tables
+            .lock()
+            .unwrap()
+            .access_tokens
+            .iter()
+            .filter(|token| token.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    fn create(&self, token: &NewAccessToken) -> Result<AccessToken, Error> {
+        let mut tables = self.tables.lock().unwrap();
+
+        tables.last_access_token_id += 1;
+        let created = AccessToken {
+            id: tables.last_access_token_id,
+            user_id: token.user_id,
+            name: String::from(token.name),
+            token_hash: String::from(token.token_hash),
+            scopes: String::from(token.scopes),
+            created_at: token.created_at,
+            revoked: false,
+        };
+        tables.access_tokens.push(created.clone());
+
+        Ok(created)
+    }
+
+    fn revoke(&self, user_id: i32, id: i32) -> Result<(), Error> {
+        let mut tables = self.tables.lock().unwrap();
+        for token in tables.access_tokens.iter_mut() {
+            if token.id == id && token.user_id == user_id {
+                token.revoked = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn authenticate(&self, token_hash: &str) -> Result<Option<(AccessToken, User)>, Error> {
+        let tables = self.tables.lock().unwrap();
+
+        let token = tables.access_tokens
+            .iter()
+            .find(|token| token.token_hash == token_hash && !token.revoked);
+
+        Ok(token.and_then(|token| {
+            tables.users
+                .iter()
+                .find(|user| user.id == token.user_id)
+                .map(|user| (token.clone(), user.clone()))
+        }))
+    }
+}
+
+impl Storage for MemoryStore {
+    fn users(&self) -> &UserStore {
+        self
+    }
+
+    fn access_tokens(&self) -> &AccessTokenStore {
+        self
+    }
+
+    fn pending_migrations(&self) -> Result<Vec<&'static str>, String> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_user<'a>(username: &'a str, email: &'a str) -> NewUser<'a> {
+        NewUser {
+            public_id: new_public_id(),
+            name: "Test User",
+            email: email,
+            username: username,
+            pass: "hash",
+            conf: false,
+            role: "student",
+        }
+    }
+
+    #[test]
+    fn create_and_find() {
+        let store = MemoryStore::new();
+
+        let first = store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+        let second = store.users().create(&new_user("jdoe", "jdoe@website.com")).unwrap();
+
+        assert_eq!(first.id, 1);
+        assert_eq!(second.id, 2);
+        assert!(!first.conf);
+        assert_eq!(store.users().find_by_username("jdoe").unwrap().id, 2);
+        assert_eq!(store.users().find(&second.public_id).unwrap().id, 2);
+        assert!(store.users().find("2").is_err());
+    }
+
+    #[test]
+    fn unique_email_and_username() {
+        let store = MemoryStore::new();
+        store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+
+        match store.users().create(&new_user("jsmith2", "jsmith@website.com")) {
+            Err(Error::EmailTaken) => {}
+            other => panic!("expected EmailTaken, got {:?}", other),
+        }
+        match store.users().create(&new_user("jsmith", "john@website.com")) {
+            Err(Error::UserTaken) => {}
+            other => panic!("expected UserTaken, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn update_keeps_own_email() {
+        let store = MemoryStore::new();
+        let user = store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+
+        let changes = UserChanges {
+            name: Some("Johnny Smith"),
+            email: Some("jsmith@website.com"),
+            username: None,
+            conf: None,
+        };
+        let updated = store.users().update(user.id, &changes).unwrap();
+
+        assert_eq!(updated.name, "Johnny Smith");
+        assert_eq!(updated.email, "jsmith@website.com");
+    }
+
+    #[test]
+    fn delete_cascades_to_tokens() {
+        let store = MemoryStore::new();
+        let user = store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+        store.access_tokens()
+            .create(&NewAccessToken {
+                user_id: user.id,
+                name: "script",
+                token_hash: "abc",
+                scopes: "read",
+                created_at: 0,
+            })
+            .unwrap();
+
+        store.users().delete(user.id).unwrap();
+
+        assert!(store.access_tokens().authenticate("abc").unwrap().is_none());
+        assert!(store.access_tokens().list(user.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn demo_users() {
+        let store = MemoryStore::demo("secret");
+
+        let tutor = store.users().find_by_username("tutor").unwrap();
+
+        assert!(tutor.conf);
+        assert_eq!(tutor.role, "tutor");
+        assert!(passwd::verify_password(&tutor.pass, DEMO_PASSWORD));
+    }
+
+    #[test]
+    fn revoked_tokens_do_not_authenticate() {
+        let store = MemoryStore::new();
+        let user = store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+        let token = store.access_tokens()
+            .create(&NewAccessToken {
+                user_id: user.id,
+                name: "script",
+                token_hash: "abc",
+                scopes: "read",
+                created_at: 0,
+            })
+            .unwrap();
+
+        assert!(store.access_tokens().authenticate("abc").unwrap().is_some());
+
+        store.access_tokens().revoke(user.id, token.id).unwrap();
+
+        assert!(store.access_tokens().authenticate("abc").unwrap().is_none());
+    }
+}
@@ -0,0 +1,107 @@
+//! Everything the routes read and write goes through these traits, so the
+//! same handlers can run against Postgres, SQLite or memory (for tests and
+//! `--demo`).
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use r2d2;
+use diesel::pg::PgConnection;
+use diesel::sqlite::SqliteConnection;
+
+use super::config::Config;
+use super::database::{Backend, ConnectionPool};
+use super::error::Error;
+use super::model::{User, NewUser, UserChanges, AccessToken, NewAccessToken};
+
+mod pg;
+mod sqlite;
+mod memory;
+
+pub use self::pg::PgStore;
+pub use self::sqlite::SqliteStore;
+pub use self::memory::{MemoryStore, DEMO_PASSWORD};
+
+/// Lookups that find nothing fail with `Error::BadUserOrPass`, and clashes
+/// with an existing email or username with `EmailTaken` or `UserTaken`, the
+/// same as the diesel errors they stand in for.
+///
+/// Users are found by their public id, as handed out in tokens and the api,
+/// and changed by the primary key on the `User` found.
+pub trait UserStore {
+    fn find(&self, public_id: &str) -> Result<User, Error>;
+
+    fn find_by_username(&self, username: &str) -> Result<User, Error>;
+
+    fn create(&self, user: &NewUser) -> Result<User, Error>;
+
+    fn update(&self, id: i32, changes: &UserChanges) -> Result<User, Error>;
+
+    fn set_password(&self, id: i32, pass: &str) -> Result<(), Error>;
+
+    fn set_role(&self, id: i32, role: &str) -> Result<(), Error>;
+
+    /// Every user in id order, or only those who haven't confirmed their
+    /// email. This is only ever shown, so it may be answered by a read
+    /// replica that lags a little behind.
+    fn list(&self, unconfirmed_only: bool) -> Result<Vec<User>, Error>;
+
+    /// Deletes the user along with everything that belongs to them.
+    fn delete(&self, id: i32) -> Result<(), Error>;
+}
+
+pub trait AccessTokenStore {
+    fn list(&self, user_id: i32) -> Result<Vec<AccessToken>, Error>;
+
+    fn create(&self, token: &NewAccessToken) -> Result<AccessToken, Error>;
+
+    /// Revokes one of `user_id`'s tokens. Tokens belonging to anyone else
+    /// are left alone.
+    fn revoke(&self, user_id: i32, id: i32) -> Result<(), Error>;
+
+    /// The unrevoked token with this hash, and who it belongs to.
+    fn authenticate(&self, token_hash: &str) -> Result<Option<(AccessToken, User)>, Error>;
+}
+
+pub trait Storage: Send + Sync {
+    fn users(&self) -> &UserStore;
+
+    fn access_tokens(&self) -> &AccessTokenStore;
+
+    /// Migrations that still have to run before requests can be served.
+    fn pending_migrations(&self) -> Result<Vec<&'static str>, String>;
+
+    /// The state of each connection pool by name, for metrics. There are none
+    /// when nothing is stored in a database.
+    fn pool_states(&self) -> Vec<(&'static str, r2d2::State)> {
+        Vec::new()
+    }
+}
+
+/// The storage backend, handed to rocket as managed state.
+#[derive(Clone)]
+pub struct Store(Arc<Storage>);
+
+impl Store {
+    pub fn new<S: Storage + 'static>(storage: S) -> Self {
+        Store(Arc::new(storage))
+    }
+
+    /// Connects to the database `DATABASE_URL` points at, postgres or sqlite.
+    pub fn connect(config: &Config) -> Result<Self, r2d2::InitializationError> {
+        match Backend::of(&config.database_url) {
+            Some(Backend::Sqlite) => {
+                Ok(Store::new(SqliteStore::new(ConnectionPool::<SqliteConnection>::new(config)?)))
+            }
+            _ => Ok(Store::new(PgStore::new(ConnectionPool::<PgConnection>::new(config)?))),
+        }
+    }
+}
+
+impl Deref for Store {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        &*self.0
+    }
+}
@@ -0,0 +1,164 @@
+use std::ops::Deref;
+
+use r2d2;
+use diesel;
+use diesel::prelude::*;
+
+use super::{Storage, UserStore, AccessTokenStore};
+use super::super::database::ConnectionPool;
+use super::super::error::Error;
+use super::super::migrations;
+use super::super::model::{User, NewUser, UserChanges, AccessToken, NewAccessToken};
+use super::super::schema::{users, access_tokens};
+
+pub struct PgStore {
+    pool: ConnectionPool,
+}
+
+impl PgStore {
+    pub fn new(pool: ConnectionPool) -> Self {
+        PgStore { pool: pool }
+    }
+}
+
+impl UserStore for PgStore {
+    fn find(&self, public_id: &str) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        Ok(users::table.filter(users::public_id.eq(public_id))
+            .first::<User>(connection.deref())?)
+    }
+
+    fn find_by_username(&self, username: &str) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        Ok(users::table.filter(users::username.eq(username))
+            .first::<User>(connection.deref())?)
+    }
+
+    fn create(&self, user: &NewUser) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        Ok(diesel::insert(user).into(users::table)
+            .get_result(connection.deref())?)
+    }
+
+    fn update(&self, id: i32, changes: &UserChanges) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        Ok(diesel::update(users::table.find(id)).set(changes)
+            .get_result(connection.deref())?)
+    }
+
+    fn set_password(&self, id: i32, pass: &str) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        diesel::update(users::table.find(id)).set(users::pass.eq(pass))
+            .execute(connection.deref())?;
+        Ok(())
+    }
+
+    fn set_role(&self, id: i32, role: &str) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        diesel::update(users::table.find(id)).set(users::role.eq(role))
+            .execute(connection.deref())?;
+        Ok(())
+    }
+
+    fn list(&self, unconfirmed_only: bool) -> Result<Vec<User>, Error> {
+        let connection = self.pool.get_read()?;
+        if unconfirmed_only {
+            Ok(users::table.filter(users::conf.eq(false))
+                .order(users::id)
+                .load(connection.deref())?)
+        } else {
+            Ok(users::table.order(users::id).load(connection.deref())?)
+        }
+    }
+
+    fn delete(&self, id: i32) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        // anything belonging to a user must reference users.id with `on delete cascade`
+        diesel::delete(users::table.find(id)).execute(connection.deref())?;
+        Ok(())
+    }
+}
+
+impl AccessTokenStore for PgStore {
+    fn list(&self, user_id: i32) -> Result<Vec<AccessToken>, Error> {
+        let connection = self.pool.get()?;
+        Ok(access_tokens::table.filter(access_tokens::user_id.eq(user_id))
+            .order(access_tokens::id)
+            .load(connection.deref())?)
+    }
+
+    fn create(&self, token: &NewAccessToken) -> Result<AccessToken, Error> {
+        let connection = self.pool.get()?;
+        Ok(diesel::insert(token).into(access_tokens::table)
+            .get_result(connection.deref())?)
+    }
+
+    fn revoke(&self, user_id: i32, id: i32) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        let target = access_tokens::table.filter(access_tokens::id.eq(id))
+            .filter(access_tokens::user_id.eq(user_id));
+
+        diesel::update(target).set(access_tokens::revoked.eq(true))
+            .execute(connection.deref())?;
+        Ok(())
+    }
+
+    fn authenticate(&self, token_hash: &str) -> Result<Option<(AccessToken, User)>, Error> {
+        // always the primary, so a revoked token stops working straight away
+        let connection = self.pool.get()?;
+        Ok(access_tokens::table.inner_join(users::table)
+            .filter(access_tokens::token_hash.eq(token_hash))
+            .filter(access_tokens::revoked.eq(false))
+            .first(connection.deref())
+            .optional()?)
+    }
+}
+
+impl Storage for PgStore {
+    fn users(&self) -> &UserStore {
+        self
+    }
+
+    fn access_tokens(&self) -> &AccessTokenStore {
+        self
+    }
+
+    fn pending_migrations(&self) -> Result<Vec<&'static str>, String> {
+        let connection = self.pool.get().map_err(|err| err.to_string())?;
+
+        migrations::pending(connection.deref())
+            .map(|pending| pending.iter().map(|migration| migration.version).collect())
+            .map_err(|err| err.to_string())
+    }
+
+    fn pool_states(&self) -> Vec<(&'static str, r2d2::State)> {
+        self.pool.states()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::super::testing::TestDb;
+
+    #[test]
+    fn list_reads_from_the_replica() {
+        // two schemas stand in for a primary and a replica that hasn't
+        // caught up: only the "replica" has any users
+        let primary = TestDb::new();
+        let replica = TestDb::seeded();
+
+        let mut config = primary.config.clone();
+        config.database_replica_url = Some(replica.config.database_url.clone());
+        let store = PgStore::new(ConnectionPool::new(&config).unwrap());
+
+        let listed: Vec<String> = store.list(false)
+            .unwrap()
+            .into_iter()
+            .map(|user| user.username)
+            .collect();
+
+        assert_eq!(listed, vec!["jsmith", "jdoe"]);
+        assert!(store.find_by_username("jsmith").is_err());
+    }
+}
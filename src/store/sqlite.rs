@@ -0,0 +1,268 @@
+use std::ops::Deref;
+
+use r2d2;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use super::{Storage, UserStore, AccessTokenStore};
+use super::super::database::ConnectionPool;
+use super::super::error::Error;
+use super::super::migrations;
+use super::super::model::{User, NewUser, UserChanges, AccessToken, NewAccessToken};
+use super::super::schema::{users, access_tokens};
+
+/// For running locally without postgres. Sqlite can't return rows from an
+/// insert or update, so writes read the row back afterwards.
+pub struct SqliteStore {
+    pool: ConnectionPool<SqliteConnection>,
+}
+
+impl SqliteStore {
+    pub fn new(pool: ConnectionPool<SqliteConnection>) -> Self {
+        SqliteStore { pool: pool }
+    }
+}
+
+impl UserStore for SqliteStore {
+    fn find(&self, public_id: &str) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        Ok(users::table.filter(users::public_id.eq(public_id))
+            .first::<User>(connection.deref())?)
+    }
+
+    fn find_by_username(&self, username: &str) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        Ok(users::table.filter(users::username.eq(username))
+            .first::<User>(connection.deref())?)
+    }
+
+    fn create(&self, user: &NewUser) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        diesel::insert(user).into(users::table).execute(connection.deref())?;
+        Ok(users::table.filter(users::username.eq(user.username))
+            .first::<User>(connection.deref())?)
+    }
+
+    fn update(&self, id: i32, changes: &UserChanges) -> Result<User, Error> {
+        let connection = self.pool.get()?;
+        diesel::update(users::table.find(id)).set(changes).execute(connection.deref())?;
+        Ok(users::table.find(id).first::<User>(connection.deref())?)
+    }
+
+    fn set_password(&self, id: i32, pass: &str) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        diesel::update(users::table.find(id)).set(users::pass.eq(pass))
+            .execute(connection.deref())?;
+        Ok(())
+    }
+
+    fn set_role(&self, id: i32, role: &str) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        diesel::update(users::table.find(id)).set(users::role.eq(role))
+            .execute(connection.deref())?;
+        Ok(())
+    }
+
+    fn list(&self, unconfirmed_only: bool) -> Result<Vec<User>, Error> {
+        let connection = self.pool.get()?;
+        if unconfirmed_only {
+            Ok(users::table.filter(users::conf.eq(false))
+                .order(users::id)
+                .load(connection.deref())?)
+        } else {
+            Ok(users::table.order(users::id).load(connection.deref())?)
+        }
+    }
+
+    fn delete(&self, id: i32) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        // cascades because the pool turns on sqlite's foreign key support
+        diesel::delete(users::table.find(id)).execute(connection.deref())?;
+        Ok(())
+    }
+}
+
+impl AccessTokenStore for SqliteStore {
+    fn list(&self, user_id: i32) -> Result<Vec<AccessToken>, Error> {
+        let connection = self.pool.get()?;
+        Ok(access_tokens::table.filter(access_tokens::user_id.eq(user_id))
+            .order(access_tokens::id)
+            .load(connection.deref())?)
+    }
+
+    fn create(&self, token: &NewAccessToken) -> Result<AccessToken, Error> {
+        let connection = self.pool.get()?;
+        diesel::insert(token).into(access_tokens::table).execute(connection.deref())?;
+        Ok(access_tokens::table.filter(access_tokens::token_hash.eq(token.token_hash))
+            .first(connection.deref())?)
+    }
+
+    fn revoke(&self, user_id: i32, id: i32) -> Result<(), Error> {
+        let connection = self.pool.get()?;
+        let target = access_tokens::table.filter(access_tokens::id.eq(id))
+            .filter(access_tokens::user_id.eq(user_id));
+
+        diesel::update(target).set(access_tokens::revoked.eq(true))
+            .execute(connection.deref())?;
+        Ok(())
+    }
+
+    fn authenticate(&self, token_hash: &str) -> Result<Option<(AccessToken, User)>, Error> {
+        let connection = self.pool.get()?;
+        Ok(access_tokens::table.inner_join(users::table)
+            .filter(access_tokens::token_hash.eq(token_hash))
+            .filter(access_tokens::revoked.eq(false))
+            .first(connection.deref())
+            .optional()?)
+    }
+}
+
+impl Storage for SqliteStore {
+    fn users(&self) -> &UserStore {
+        self
+    }
+
+    fn access_tokens(&self) -> &AccessTokenStore {
+        self
+    }
+
+    fn pending_migrations(&self) -> Result<Vec<&'static str>, String> {
+        let connection = self.pool.get().map_err(|err| err.to_string())?;
+
+        migrations::pending(connection.deref())
+            .map(|pending| pending.iter().map(|migration| migration.version).collect())
+            .map_err(|err| err.to_string())
+    }
+
+    fn pool_states(&self) -> Vec<(&'static str, r2d2::State)> {
+        self.pool.states()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rand::{thread_rng, Rng};
+
+    use super::super::super::config::Config;
+    use super::super::super::model::new_public_id;
+
+    // a file rather than `:memory:`, which would give each pooled connection
+    // a database of its own
+    struct TempDb {
+        path: PathBuf,
+        store: SqliteStore,
+    }
+
+    impl TempDb {
+        fn new() -> TempDb {
+            let mut bytes = [0u8; 8];
+            thread_rng().fill_bytes(&mut bytes);
+            let name: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let path = env::temp_dir().join(format!("pupil-test-{}.db", name));
+
+            let mut config = Config::load_demo().unwrap();
+            config.database_url = format!("sqlite://{}", path.display());
+            config.database_replica_url = None;
+            config.pool_size = 2;
+            config.pool_connect_retries = 0;
+
+            let pool = ConnectionPool::<SqliteConnection>::new(&config).unwrap();
+            migrations::run_pending(pool.get().unwrap().deref()).unwrap();
+
+            TempDb {
+                path: path,
+                store: SqliteStore::new(pool),
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn new_user<'a>(username: &'a str, email: &'a str) -> NewUser<'a> {
+        NewUser {
+            public_id: new_public_id(),
+            name: "Test User",
+            email: email,
+            username: username,
+            pass: "hash",
+            conf: false,
+            role: "student",
+        }
+    }
+
+    #[test]
+    fn create_and_update() {
+        let db = TempDb::new();
+
+        let user = db.store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+        assert_eq!(user.id, 1);
+        assert_eq!(user.role, "student");
+        assert!(!user.conf);
+        assert_eq!(db.store.users().find(&user.public_id).unwrap().id, 1);
+
+        let changes = UserChanges {
+            name: Some("Johnny Smith"),
+            email: None,
+            username: None,
+            conf: Some(true),
+        };
+        let updated = db.store.users().update(user.id, &changes).unwrap();
+
+        assert_eq!(updated.name, "Johnny Smith");
+        assert!(updated.conf);
+    }
+
+    #[test]
+    fn unique_violations() {
+        let db = TempDb::new();
+        let user = db.store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+
+        match db.store.users().create(&new_user("jsmith2", "jsmith@website.com")) {
+            Err(Error::EmailTaken) => {}
+            other => panic!("expected EmailTaken, got {:?}", other),
+        }
+        match db.store.users().create(&new_user("jsmith", "john@website.com")) {
+            Err(Error::UserTaken) => {}
+            other => panic!("expected UserTaken, got {:?}", other),
+        }
+
+        let mut clash = new_user("jdoe", "jdoe@website.com");
+        clash.public_id = user.public_id;
+        match db.store.users().create(&clash) {
+            Err(Error::DatabaseError(_)) => {}
+            other => panic!("expected DatabaseError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delete_cascades_to_tokens() {
+        let db = TempDb::new();
+        let user = db.store.users().create(&new_user("jsmith", "jsmith@website.com")).unwrap();
+        db.store
+            .access_tokens()
+            .create(&NewAccessToken {
+                user_id: user.id,
+                name: "script",
+                token_hash: "abc",
+                scopes: "read",
+                created_at: 0,
+            })
+            .unwrap();
+        assert!(db.store.access_tokens().authenticate("abc").unwrap().is_some());
+
+        db.store.users().delete(user.id).unwrap();
+
+        assert!(db.store.access_tokens().list(user.id).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+//! Helpers for tests that need a database. Each `TestDb` lives in its own
+//! throwaway schema, so tests can run in parallel and a panicking test can't
+//! leave anything behind for the next one.
+
+use std::ops::Deref;
+
+use rand::{thread_rng, Rng};
+
+use rocket::{self, Rocket};
+use rocket::http::Method;
+use rocket::testing::MockRequest;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+
+use r2d2::PooledConnection;
+use r2d2_diesel::ConnectionManager;
+
+use serde_json;
+
+use super::assets::Assets;
+use super::config::Config;
+use super::database::ConnectionPool;
+use super::store::{Store, PgStore};
+use super::hasher::Hasher;
+use super::keys::JwtKeys;
+use super::migrations;
+use super::model::{User, NewUser, UserToken, Role, new_public_id};
+use super::passwd;
+use super::session::AuthCookie;
+
+/// The password fixture users get unless they are given another.
+pub const PASSWORD: &'static str = "test";
+
+// PASSWORD, hashed ahead of time so fixtures don't each pay for argon2
+const PASSWORD_HASH: &'static str = "$argon2i$m=4096,t=10,p=1,keyid=c2VjcmV0,data=anNtaXRo$elvek\
+                                     jRXU/2NqdkYTxb8T155N1QiXMAYhTWdX+vtyOm+kM81W27CsdOsMabqYkYaM\
+                                     3qKdhOKZuxS0v8bZojvLg$Mqnr5Isv3B3LzWU8WjNFDSklhOf8sANtS41PHBV\
+                                     JtFk";
+
+/// A migrated schema of its own, dropped again when this goes out of scope.
+pub struct TestDb {
+    schema: String,
+    admin_url: String,
+    pub config: Config,
+    pub pool: ConnectionPool,
+}
+
+// libpq sets the search path for every connection made with this url
+fn with_search_path(url: &str, schema: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}options=-csearch_path%3D{}", url, separator, schema)
+}
+
+impl TestDb {
+    pub fn new() -> TestDb {
+        let base = Config::load().unwrap();
+
+        let mut bytes = [0u8; 8];
+        thread_rng().fill_bytes(&mut bytes);
+        let suffix: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let schema = format!("test_{}", suffix);
+
+        PgConnection::establish(&base.database_url)
+            .unwrap()
+            .batch_execute(&format!("CREATE SCHEMA {}", schema))
+            .unwrap();
+
+        let mut config = base.clone();
+        config.database_url = with_search_path(&base.database_url, &schema);
+        config.database_replica_url = None;
+        config.pool_size = 2;
+        config.pool_connect_retries = 0;
+
+        let db = TestDb {
+            schema: schema,
+            admin_url: base.database_url,
+            pool: ConnectionPool::new(&config).unwrap(),
+            config: config,
+        };
+        migrations::run_pending(db.connection().deref()).unwrap();
+
+        db
+    }
+
+    /// A `TestDb` holding the two users most tests are written against:
+    /// jsmith (id 1, confirmed) and jdoe (id 2, not confirmed).
+    pub fn seeded() -> TestDb {
+        let db = TestDb::new();
+        UserBuilder::new("jsmith").name("John Smith").create(&db);
+        UserBuilder::new("jdoe").name("Jane Doe").unconfirmed().create(&db);
+        db
+    }
+
+    pub fn connection(&self) -> PooledConnection<ConnectionManager<PgConnection>> {
+        self.pool.get().unwrap()
+    }
+
+    pub fn user(&self, username: &str) -> User {
+        use super::schema::users;
+
+        users::table.filter(users::username.eq(username))
+            .first::<User>(self.connection().deref())
+            .unwrap()
+    }
+
+    /// A rocket managing everything the routes expect, ready for routes to be
+    /// mounted on it.
+    pub fn rocket(&self) -> Rocket {
+        rocket::ignite()
+            .manage(Store::new(PgStore::new(self.pool.clone())))
+            .manage(Hasher::with_capacity(1, 8))
+            .manage(JwtKeys::new(&self.config).unwrap())
+            .manage(Assets::new(&self.config))
+            .manage(self.config.clone())
+    }
+
+    /// A jwt for `user`, as login would issue it.
+    pub fn jwt(&self, user: &User) -> String {
+        UserToken::new(user.clone()).construct_jwt(&JwtKeys::new(&self.config).unwrap()).unwrap()
+    }
+
+    /// A request signed in as `user` with an auth cookie.
+    pub fn authed<S: AsRef<str>>(&self, method: Method, uri: S, user: &User) -> MockRequest {
+        MockRequest::new(method, uri).cookie(AuthCookie::new(&self.config).sent(&self.jwt(user)))
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        if let Ok(connection) = PgConnection::establish(&self.admin_url) {
+            let _ = connection.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema));
+        }
+    }
+}
+
+/// Builds a user for a test. Without any changes it is a confirmed student
+/// whose password is `PASSWORD`.
+pub struct UserBuilder {
+    name: String,
+    email: String,
+    username: String,
+    password: Option<String>,
+    conf: bool,
+    role: Role,
+}
+
+impl UserBuilder {
+    pub fn new(username: &str) -> Self {
+        UserBuilder {
+            name: String::from(username),
+            email: format!("{}@website.com", username),
+            username: String::from(username),
+            password: None,
+            conf: true,
+            role: Role::Student,
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = String::from(name);
+        self
+    }
+
+    pub fn email(mut self, email: &str) -> Self {
+        self.email = String::from(email);
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(String::from(password));
+        self
+    }
+
+    pub fn unconfirmed(mut self) -> Self {
+        self.conf = false;
+        self
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn create(self, db: &TestDb) -> User {
+        use super::schema::users;
+
+        let pass = match self.password {
+            Some(ref password) => {
+                passwd::hash_password(&self.username, password, &db.config.hash_secret)
+            }
+            None => String::from(PASSWORD_HASH),
+        };
+
+        diesel::insert(&NewUser {
+                public_id: new_public_id(),
+                name: &self.name,
+                email: &self.email,
+                username: &self.username,
+                pass: &pass,
+                conf: self.conf,
+                role: self.role.as_str(),
+            })
+            .into(users::table)
+            .get_result(db.connection().deref())
+            .unwrap()
+    }
+}
+
+/// Pulls the message out of an `Error` response body.
+pub fn error_message(body: Option<String>) -> Option<String> {
+    body.and_then(|b| serde_json::from_str::<serde_json::Value>(&b).ok())
+        .and_then(|b| b["error"].as_str().map(String::from))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use diesel::expression::dsl::sql;
+    use diesel::types::BigInt;
+
+    fn schema_exists(db: &TestDb, schema: &str) -> bool {
+        let query = format!("SELECT count(*) FROM information_schema.schemata \
+                             WHERE schema_name = '{}'",
+                            schema);
+        let count: i64 = sql::<BigInt>(&query).get_result(db.connection().deref()).unwrap();
+        count == 1
+    }
+
+    #[test]
+    fn schemas_are_isolated() {
+        use super::super::schema::users;
+
+        let first = TestDb::seeded();
+        let second = TestDb::new();
+
+        let count = |db: &TestDb| {
+            users::table.count().get_result::<i64>(db.connection().deref()).unwrap()
+        };
+
+        assert_eq!(count(&first), 2);
+        assert_eq!(count(&second), 0);
+    }
+
+    #[test]
+    fn builds_users() {
+        let db = TestDb::new();
+        let user = UserBuilder::new("admin")
+            .email("admin@pupil.io")
+            .password("hunter22")
+            .role(Role::Admin)
+            .create(&db);
+
+        assert_eq!(user.email, "admin@pupil.io");
+        assert_eq!(user.role, "admin");
+        assert!(user.conf);
+        assert!(passwd::verify_password(&user.pass, "hunter22"));
+        assert!(!passwd::verify_password(&user.pass, PASSWORD));
+    }
+
+    #[test]
+    fn schema_dropped_with_db() {
+        let db = TestDb::new();
+        let schema = db.schema.clone();
+
+        {
+            let other = TestDb::new();
+            assert!(schema_exists(&db, &other.schema));
+            let other_schema = other.schema.clone();
+            drop(other);
+            assert!(!schema_exists(&db, &other_schema));
+        }
+
+        assert!(schema_exists(&db, &schema));
+    }
+}